@@ -0,0 +1,138 @@
+#version 450
+
+#include "includes.glsl"
+
+/*
+Brush shapes. Must match `BrushShape` in brush.rs. Listed cells aren't a shape but write the cells
+given as (index, matter, matter to replace) in the segments buffer, e.g. a flood fill or undo.
+*/
+#define SHAPE_ROUND 0u
+#define SHAPE_SQUARE 1u
+#define SHAPE_SPRAY 2u
+#define SHAPE_RECTANGLE 3u
+#define SHAPE_LISTED_CELLS 4u
+
+/// Brush replacing any matter
+#define REPLACE_ANY 0xffffffffu
+
+/// Must match `BrushParams` in brush.rs
+struct Brush {
+    uint shape;
+    uint matter;
+    uint replace_only;
+    float radius;
+    float density;
+    uint seed;
+    /// Cell of the first invocation, the dispatch covering only the stroke's bounds
+    ivec2 offset;
+    uint segments_len;
+    /// Number of changes written, counted up by the kernel
+    uint changes_len;
+};
+
+/// Must match `CellChange` in history.rs
+struct CellChange {
+    uint index;
+    uint before;
+    uint after;
+};
+
+layout(set = 0, binding = 5) restrict buffer BrushBuffer { Brush brush; };
+/// Segments from cell xy to cell zw. The rectangle spans from corner xy to corner zw.
+layout(set = 0, binding = 6) restrict readonly buffer SegmentsBuffer { ivec4 segments[]; };
+layout(set = 0, binding = 7) restrict writeonly buffer ChangesBuffer { CellChange changes[]; };
+
+/// Distance from p to the closest point of the segment from a to b
+float segment_distance(vec2 p, vec2 a, vec2 b) {
+    vec2 ab = b - a;
+    float len2 = dot(ab, ab);
+    float t = len2 > 0.0 ? clamp(dot(p - a, ab) / len2, 0.0, 1.0) : 0.0;
+    return distance(p, a + t * ab);
+}
+
+/// Whether the square of radius r moved from a to b covers p. Along each axis p is covered
+/// for a range of the square's position t along the segment, those ranges must overlap.
+bool in_square_segment(vec2 p, vec2 a, vec2 b, float r) {
+    float t_min = 0.0;
+    float t_max = 1.0;
+    for (int axis = 0; axis < 2; axis++) {
+        float d = b[axis] - a[axis];
+        float low = p[axis] - a[axis] - r;
+        float high = p[axis] - a[axis] + r;
+        if (d == 0.0) {
+            if (low > 0.0 || high < 0.0) {
+                return false;
+            }
+        } else {
+            t_min = max(t_min, min(low / d, high / d));
+            t_max = min(t_max, max(low / d, high / d));
+        }
+    }
+    return t_min <= t_max;
+}
+
+/// Spray covers a random share of the round brush, the same for the same cell & seed
+bool is_sprayed(ivec2 pos) {
+    uint hash = pcg_hash(pcg_hash(brush.seed ^ uint(pos.x)) ^ uint(pos.y));
+    return float(hash) / 4294967295.0 < brush.density;
+}
+
+/// Must match the cpu reference `covered` in brush.rs tests
+bool is_covered(ivec2 pos) {
+    for (uint i = 0; i < brush.segments_len; i++) {
+        ivec2 a = segments[i].xy;
+        ivec2 b = segments[i].zw;
+        bool covered;
+        if (brush.shape == SHAPE_RECTANGLE) {
+            covered = all(greaterThanEqual(pos, min(a, b))) && all(lessThanEqual(pos, max(a, b)));
+        } else if (brush.shape == SHAPE_SQUARE) {
+            covered = in_square_segment(vec2(pos), vec2(a), vec2(b), floor(brush.radius));
+        } else {
+            // Rounds ties up, where round() may go either way
+            covered = floor(segment_distance(vec2(pos), vec2(a), vec2(b)) + 0.5) <= brush.radius;
+            if (brush.shape == SHAPE_SPRAY) {
+                covered = covered && is_sprayed(pos);
+            }
+        }
+        if (covered) {
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Drawing writes the current cells in place, each invocation touching only its own cell
+void main() {
+    int index;
+    uint matter = brush.matter;
+    uint replace_only = brush.replace_only;
+    if (brush.shape == SHAPE_LISTED_CELLS) {
+        // One invocation per listed cell
+        uint i = gl_GlobalInvocationID.y * gl_NumWorkGroups.x * gl_WorkGroupSize.x + gl_GlobalInvocationID.x;
+        if (i >= brush.segments_len) {
+            return;
+        }
+        index = segments[i].x;
+        matter = uint(segments[i].y);
+        replace_only = uint(segments[i].z);
+    } else {
+        ivec2 pos = brush.offset + ivec2(gl_GlobalInvocationID.xy);
+        if (!is_inside_sim_canvas(pos) || !is_covered(pos)) {
+            return;
+        }
+        index = get_index(pos);
+    }
+    Cell current = matter_in[index];
+    uint before = current.matter;
+    // Cells of the matter already are left as they are, unless continuous rules changed their state
+    bool unchanged = before == matter && current.state == initial_state(matter);
+    if (unchanged || (replace_only != REPLACE_ANY && before != replace_only)) {
+        return;
+    }
+    // Drawn cells are new, at the matter's temperature with a random shade of its color
+    Cell cell = matter_cell(matter);
+    set_cell_color_variation(cell, int(pcg_hash(brush.seed ^ pcg_hash(uint(index))) >> 24) - 128);
+    matter_in[index] = cell;
+    uint change = atomicAdd(brush.changes_len, 1u);
+    changes[change] = CellChange(uint(index), before, matter);
+}
@@ -0,0 +1,56 @@
+/*
+Cell state, the layout of the matter grids. `Cell` in cell.rs is generated from this struct.
+*/
+struct Cell {
+    uint matter;
+    /// Degrees Celsius
+    float temperature;
+    /// Cells moved this step, x in the low and y in the high 16 bits as signed 8.8 fixed point
+    uint velocity;
+    /// Steps since the matter appeared in the low 16 bits, saturating. Color variation as a
+    /// signed byte in the high 8 bits.
+    uint lifetime_variation;
+    /// State 0.0 - 1.0 of continuous rules, see lenia.glsl. Rules of matter leave it as is.
+    float state;
+};
+
+/// Temperature of new cells that aren't given a matter's. Must match `AMBIENT_TEMPERATURE` in
+/// cell.rs.
+#define AMBIENT_TEMPERATURE 20.0
+#define MAX_LIFETIME 0xffffu
+
+/// State new cells of matter start with, full for any matter but the empty one. Must match
+/// `Cell::new` in cell.rs.
+float initial_state(uint matter) {
+    return matter == empty_matter ? 0.0 : 1.0;
+}
+
+Cell new_cell(uint matter) {
+    return Cell(matter, AMBIENT_TEMPERATURE, 0u, 0u, initial_state(matter));
+}
+
+vec2 cell_velocity(Cell cell) {
+    return vec2(int(cell.velocity << 16) >> 16, int(cell.velocity) >> 16) / 256.0;
+}
+
+void set_cell_velocity(inout Cell cell, vec2 velocity) {
+    ivec2 fixed_point = clamp(ivec2(round(velocity * 256.0)), ivec2(-32768), ivec2(32767));
+    cell.velocity = (uint(fixed_point.x) & 0xffffu) | (uint(fixed_point.y) << 16);
+}
+
+uint cell_lifetime(Cell cell) {
+    return cell.lifetime_variation & MAX_LIFETIME;
+}
+
+void set_cell_lifetime(inout Cell cell, uint lifetime) {
+    cell.lifetime_variation = (cell.lifetime_variation & ~MAX_LIFETIME) | min(lifetime, MAX_LIFETIME);
+}
+
+/// Color variation -128 - 127, shading the matter's color
+int cell_color_variation(Cell cell) {
+    return int(cell.lifetime_variation) >> 24;
+}
+
+void set_cell_color_variation(inout Cell cell, int variation) {
+    cell.lifetime_variation = (cell.lifetime_variation & 0x00ffffffu) | (uint(variation) << 24);
+}
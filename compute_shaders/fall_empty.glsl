@@ -0,0 +1,39 @@
+#version 450
+
+#include "includes.glsl"
+
+/// Powders and liquids fall into empty cells and sink through lighter liquids & gases
+bool falls_into(uint from, uint to) {
+    return (is_powder(from) || is_liquid(from)) && is_fluid(to) &&
+        (is_empty(to) || matter_density(from) > matter_density(to));
+}
+
+bool wants_to_fall(ivec2 pos) {
+    return is_inside_sim_canvas(pos + DOWN) && falls_into(read_matter(pos), read_matter(pos + DOWN));
+}
+
+/// Matter can fall if the cell below is empty or it swaps with matter that is staying in place.
+/// Both cells of a swap evaluate this same rule, thus they always agree on the move.
+bool falls(ivec2 pos) {
+    return wants_to_fall(pos) && (is_empty(read_matter(pos + DOWN)) || !wants_to_fall(pos + DOWN));
+}
+
+/// Each cell decides only its own next state: a cell receives the cell falling from above, or
+/// takes the place of what is below if it falls itself. Thus no two invocations write the same cell.
+void fall_empty(ivec2 pos) {
+    Cell cell = read_cell(pos);
+    if (is_inside_sim_canvas(pos + UP) && falls(pos + UP)) {
+        cell = moved_cell(pos + UP, DOWN);
+    } else if (falls(pos)) {
+        cell = moved_cell(pos + DOWN, UP);
+    }
+    write_cell(pos, cell);
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    fall_empty(pos);
+}
@@ -0,0 +1,49 @@
+#version 450
+
+#include "includes.glsl"
+
+const ivec2 NEIGHBORS[4] = ivec2[4](UP, DOWN, LEFT, RIGHT);
+
+/// Heat flows between neighbors as well as the worse conductor of both lets it
+float conductivity_between(uint a, uint b) {
+    return min(matter_conductivity(a), matter_conductivity(b));
+}
+
+/// Temperature evens out with the 4 neighbors, each pair exchanging the same heat so that heat is
+/// conserved. The canvas edge insulates. Cells heated or cooled past a phase transition of their
+/// matter become its product, keeping their temperature. Must match `heat` in cpu_simulator.rs.
+void heat(ivec2 pos) {
+    Cell cell = read_cell(pos);
+    // Precise keeps the compiler from fusing operations, so that the cpu reference computes the
+    // same temperatures
+    precise float flow = 0.0;
+    for (int i = 0; i < 4; i++) {
+        ivec2 neighbor = pos + NEIGHBORS[i];
+        if (is_inside_sim_canvas(neighbor)) {
+            Cell other = read_cell(neighbor);
+            flow += conductivity_between(cell.matter, other.matter) * (other.temperature - cell.temperature);
+        }
+    }
+    precise float temperature = cell.temperature + 0.25 * flow;
+    cell.temperature = temperature;
+    MatterProperties properties = matter_properties[cell.matter];
+    uint matter = cell.matter;
+    if (temperature > properties.heated_above) {
+        matter = properties.heated_into;
+    } else if (temperature < properties.cooled_below) {
+        matter = properties.cooled_into;
+    }
+    if (matter != cell.matter) {
+        cell.matter = matter;
+        set_cell_lifetime(cell, 0u);
+    }
+    write_cell(pos, cell);
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    heat(pos);
+}
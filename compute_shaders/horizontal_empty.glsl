@@ -0,0 +1,49 @@
+#version 450
+
+#include "includes.glsl"
+
+/// Direction (LEFT, RIGHT or NO_MOVE) in which the liquid or gas at pos spreads. Liquids that
+/// can't fall and gases that can't rise any further flow sideways into an empty neighbor, picking
+/// one at random if both are empty.
+ivec2 spread_direction(ivec2 pos) {
+    uint matter = read_matter(pos);
+    bool spreads = (is_liquid(matter) && is_blocked(pos, DOWN)) || (is_gas(matter) && is_blocked(pos, UP));
+    if (!spreads) {
+        return NO_MOVE;
+    }
+    bool left = is_empty_at(pos + LEFT);
+    bool right = is_empty_at(pos + RIGHT);
+    if (left && right) {
+        return random_bool(pos, RANDOM_SPREAD) ? LEFT : RIGHT;
+    }
+    return left ? LEFT : right ? RIGHT : NO_MOVE;
+}
+
+/// Same conflict resolution as in sliding: matter flowing left (from the right) wins an empty cell
+/// targeted from both sides, and swaps places with it.
+void horizontal_empty(ivec2 pos) {
+    Cell cell = read_cell(pos);
+    if (is_empty(cell.matter)) {
+        if (is_inside_sim_canvas(pos + RIGHT) && spread_direction(pos + RIGHT) == LEFT) {
+            cell = moved_cell(pos + RIGHT, LEFT);
+        } else if (is_inside_sim_canvas(pos + LEFT) && spread_direction(pos + LEFT) == RIGHT) {
+            cell = moved_cell(pos + LEFT, RIGHT);
+        }
+    } else {
+        ivec2 dir = spread_direction(pos);
+        bool accepted = dir == LEFT ||
+            (dir == RIGHT && !(is_inside_sim_canvas(pos + 2 * RIGHT) && spread_direction(pos + 2 * RIGHT) == LEFT));
+        if (accepted) {
+            cell = moved_cell(pos + dir, -dir);
+        }
+    }
+    write_cell(pos, cell);
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    horizontal_empty(pos);
+}
@@ -0,0 +1,219 @@
+/*
+Specialization constants
+*/
+layout(constant_id = 0) const int canvas_size_x = 1;
+layout(constant_id = 1) const int canvas_size_y = 1;
+layout(constant_id = 2) const uint empty_matter = 1;
+layout(local_size_x_id = 3, local_size_y_id = 4, local_size_z = 1) in;
+
+#include "cell.glsl"
+
+/*
+Matter. Each cell holds a matter id, which indexes the matter properties buffer. Matters are defined
+in assets/matter.ron. States must match `MatterState` in matter.rs.
+*/
+#define STATE_EMPTY 0u
+#define STATE_SOLID 1u
+#define STATE_POWDER 2u
+#define STATE_LIQUID 3u
+#define STATE_GAS 4u
+
+struct MatterProperties {
+    uint color;
+    uint state;
+    float density;
+    uint reactions_start;
+    uint reactions_len;
+    float temperature;
+    float conductivity;
+    /// Phase transitions: hotter cells become `heated_into`, colder ones `cooled_into`. Matters
+    /// without a transition have infinite temperatures.
+    float heated_above;
+    uint heated_into;
+    float cooled_below;
+    uint cooled_into;
+};
+
+/// Matter touching `reagent` becomes `product`
+struct MatterReaction {
+    uint reagent;
+    uint product;
+};
+
+/*
+Buffers
+*/
+layout(set = 0, binding = 0) restrict buffer MatterInBuffer { Cell matter_in[]; };
+layout(set = 0, binding = 1) restrict writeonly buffer MatterOutBuffer { Cell matter_out[]; };
+layout(set = 0, binding = 2, rgba8) restrict uniform writeonly image2D canvas_img;
+layout(set = 0, binding = 3) restrict readonly buffer MatterPropertiesBuffer { MatterProperties matter_properties[]; };
+layout(set = 0, binding = 4) restrict readonly buffer MatterReactionsBuffer { MatterReaction matter_reactions[]; };
+
+/*
+Push constants, the same for all kernels of a step
+*/
+layout(push_constant) uniform PushConstants {
+    uint seed;
+    uint steps;
+} push_constants;
+
+/*
+Directions. Gravity pulls towards y = 0.
+*/
+#define UP ivec2(0, 1)
+#define DOWN ivec2(0, -1)
+#define LEFT ivec2(-1, 0)
+#define RIGHT ivec2(1, 0)
+#define UP_LEFT (UP + LEFT)
+#define UP_RIGHT (UP + RIGHT)
+#define DOWN_LEFT (DOWN + LEFT)
+#define DOWN_RIGHT (DOWN + RIGHT)
+#define NO_MOVE ivec2(0, 0)
+
+/*
+Utility functions to be used in the various kernels:
+*/
+
+int get_index(ivec2 pos) {
+    return pos.y * canvas_size_x + pos.x;
+}
+
+bool is_inside_sim_canvas(ivec2 pos) {
+    return pos.x >= 0 && pos.x < canvas_size_x &&
+    pos.y >= 0 && pos.y < canvas_size_y;
+}
+
+/// Cell of the current invocation, false if it's outside of the canvas. Canvas size need not be a
+/// multiple of work group size, thus the last work groups have invocations outside, which kernels
+/// skip by returning early.
+bool get_current_sim_pos(out ivec2 pos) {
+    pos = ivec2(gl_GlobalInvocationID.xy);
+    return is_inside_sim_canvas(pos);
+}
+
+Cell read_cell(ivec2 pos) {
+    return matter_in[get_index(pos)];
+}
+
+/// Kernels write every cell of the next grid, including the fields they don't change
+void write_cell(ivec2 pos, Cell cell) {
+    matter_out[get_index(pos)] = cell;
+}
+
+uint read_matter(ivec2 pos) {
+    return matter_in[get_index(pos)].matter;
+}
+
+/// Cell at pos moving by dir, its velocity adding up the moves of the step
+Cell moved_cell(ivec2 pos, ivec2 dir) {
+    Cell cell = read_cell(pos);
+    set_cell_velocity(cell, cell_velocity(cell) + vec2(dir));
+    return cell;
+}
+
+void write_image_color(ivec2 pos, vec4 color) {
+    imageStore(canvas_img, pos, color);
+}
+
+uint matter_state(uint matter) {
+    return matter_properties[matter].state;
+}
+
+float matter_density(uint matter) {
+    return matter_properties[matter].density;
+}
+
+uint matter_color(uint matter) {
+    return matter_properties[matter].color;
+}
+
+float matter_conductivity(uint matter) {
+    return matter_properties[matter].conductivity;
+}
+
+/// New cell of matter at the matter's initial temperature, e.g. drawn by the brush or born in
+/// Life. Must match `Cell::of_matter` in cell.rs.
+Cell matter_cell(uint matter) {
+    Cell cell = new_cell(matter);
+    cell.temperature = matter_properties[matter].temperature;
+    return cell;
+}
+
+bool is_empty(uint matter) {
+    return matter == empty_matter;
+}
+
+bool is_solid(uint matter) {
+    return matter_state(matter) == STATE_SOLID;
+}
+
+bool is_powder(uint matter) {
+    return matter_state(matter) == STATE_POWDER;
+}
+
+bool is_liquid(uint matter) {
+    return matter_state(matter) == STATE_LIQUID;
+}
+
+bool is_gas(uint matter) {
+    return matter_state(matter) == STATE_GAS;
+}
+
+/// Matter others can sink or rise through
+bool is_fluid(uint matter) {
+    return is_empty(matter) || is_liquid(matter) || is_gas(matter);
+}
+
+/// Outside of canvas counts as solid wall, thus matter never moves there
+bool is_empty_at(ivec2 pos) {
+    return is_inside_sim_canvas(pos) && is_empty(read_matter(pos));
+}
+
+/// Blocked means that matter can't move further in that direction
+bool is_blocked(ivec2 pos, ivec2 dir) {
+    return !is_empty_at(pos + dir);
+}
+
+/*
+Colors. Our swapchain is in SRGB color space (default by bevy_vulkano), but the canvas image is
+UNORM (only way to ImageStore), thus colors written to it must be converted to linear space.
+*/
+
+// 0-1 linear  from  0-255 sRGB
+vec3 linear_from_srgb(vec3 srgb) {
+    bvec3 cutoff = lessThan(srgb, vec3(10.31475));
+    vec3 lower = srgb / vec3(3294.6);
+    vec3 higher = pow((srgb + vec3(14.025)) / vec3(269.025), vec3(2.4));
+    return mix(higher, lower, cutoff);
+}
+
+vec4 linear_from_srgba(vec4 srgba) {
+    return vec4(linear_from_srgb(srgba.rgb * 255.0), srgba.a);
+}
+
+/*
+Random numbers. Each number is a hash of seed, step, position and a salt naming the random choice,
+thus every invocation evaluating the same choice for a cell gets the same number, and a seed with
+an initial grid always reproduces the same run. Must match `random_uint` in cpu_simulator.rs.
+*/
+#define RANDOM_SLIDE 1u
+#define RANDOM_SPREAD 2u
+
+/// PCG hash from "Hash Functions for GPU Rendering" (Jarzynski & Olano, 2020)
+uint pcg_hash(uint v) {
+    uint state = v * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+uint random_uint(ivec2 pos, uint salt) {
+    uint hash = pcg_hash(push_constants.seed);
+    hash = pcg_hash(hash ^ uint(pos.x));
+    hash = pcg_hash(hash ^ uint(pos.y));
+    hash = pcg_hash(hash ^ push_constants.steps);
+    return pcg_hash(hash ^ salt);
+}
+
+bool random_bool(ivec2 pos, uint salt) {
+    return (random_uint(pos, salt) >> 31u) == 1u;
+}
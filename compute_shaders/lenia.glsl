@@ -0,0 +1,61 @@
+#version 450
+
+#include "includes.glsl"
+
+/*
+Lenia: each cell's continuous state 0.0 - 1.0 evolves, while its matter stays as is. Cells grow or
+shrink by the growth function of their potential, the average state around them weighted by a ring
+shaped kernel of `radius` cells. The canvas wraps around. Must match `LeniaRule` & `GrowthFunction`
+in lenia.rs.
+*/
+#define GROWTH_GAUSSIAN 0u
+#define GROWTH_POLYNOMIAL 1u
+#define GROWTH_STEP 2u
+
+layout(constant_id = 5) const int radius = 13;
+/// Potential growing fastest
+layout(constant_id = 6) const float mu = 0.15;
+/// Width of the growth function around `mu`
+layout(constant_id = 7) const float sigma = 0.015;
+/// Time step, the share of growth applied per step
+layout(constant_id = 8) const float dt = 0.1;
+/// One of the GROWTH_* defines
+layout(constant_id = 9) const uint growth_function = 0;
+
+/// Kernel weights for offsets from -radius to radius row by row, normalized to sum up to 1.0.
+/// Computed once per rule by `LeniaRule::kernel_weights` in lenia.rs.
+layout(set = 0, binding = 5) restrict readonly buffer KernelBuffer { float kernel_weights[]; };
+
+float read_state(ivec2 pos) {
+    ivec2 size = ivec2(canvas_size_x, canvas_size_y);
+    return read_cell(((pos % size) + size) % size).state;
+}
+
+/// Growth -1.0 - 1.0 of a potential
+float growth(float potential) {
+    float d = potential - mu;
+    if (growth_function == GROWTH_POLYNOMIAL) {
+        return 2.0 * pow(max(0.0, 1.0 - d * d / (9.0 * sigma * sigma)), 4.0) - 1.0;
+    } else if (growth_function == GROWTH_STEP) {
+        return abs(d) <= sigma ? 1.0 : -1.0;
+    }
+    return 2.0 * exp(-d * d / (2.0 * sigma * sigma)) - 1.0;
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    float potential = 0.0;
+    int i = 0;
+    for (int y = -radius; y <= radius; y++) {
+        for (int x = -radius; x <= radius; x++) {
+            potential += kernel_weights[i] * read_state(pos + ivec2(x, y));
+            i++;
+        }
+    }
+    Cell cell = read_cell(pos);
+    cell.state = clamp(cell.state + dt * growth(potential), 0.0, 1.0);
+    write_cell(pos, cell);
+}
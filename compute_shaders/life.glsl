@@ -0,0 +1,71 @@
+#version 450
+
+#include "includes.glsl"
+
+/*
+Life-like rule as bit masks of living neighbor counts: bit n of `birth` set means that an empty cell
+with n living neighbors is born, bit n of `survive` that a living cell with n living neighbors
+survives. Must match `LifeRule` in life.rs. Defaults are Conway's B3/S23.
+*/
+layout(constant_id = 5) const uint birth = 8;
+layout(constant_id = 6) const uint survive = 12;
+
+const ivec2 NEIGHBORS[8] = ivec2[8](
+    UP_LEFT, UP, UP_RIGHT,
+    LEFT, RIGHT,
+    DOWN_LEFT, DOWN, DOWN_RIGHT
+);
+
+/// Any matter is alive. Outside of canvas counts as dead.
+bool is_alive_at(ivec2 pos) {
+    return is_inside_sim_canvas(pos) && !is_empty(read_matter(pos));
+}
+
+/// Newborn cells take the most common matter of their living neighbors, the first one on ties
+uint newborn_matter(ivec2 pos) {
+    uint newborn = empty_matter;
+    uint newborn_count = 0;
+    for (int i = 0; i < 8; i++) {
+        if (!is_alive_at(pos + NEIGHBORS[i])) {
+            continue;
+        }
+        uint matter = read_matter(pos + NEIGHBORS[i]);
+        uint count = 0;
+        for (int j = 0; j < 8; j++) {
+            if (is_alive_at(pos + NEIGHBORS[j]) && read_matter(pos + NEIGHBORS[j]) == matter) {
+                count++;
+            }
+        }
+        if (count > newborn_count) {
+            newborn = matter;
+            newborn_count = count;
+        }
+    }
+    return newborn;
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    uint neighbors = 0;
+    for (int i = 0; i < 8; i++) {
+        if (is_alive_at(pos + NEIGHBORS[i])) {
+            neighbors++;
+        }
+    }
+    Cell cell = read_cell(pos);
+    uint count_bit = 1u << neighbors;
+    if (is_empty(cell.matter)) {
+        // Newborns start at their matter's initial temperature like drawn cells
+        uint matter = (birth & count_bit) != 0u ? newborn_matter(pos) : empty_matter;
+        write_cell(pos, matter_cell(matter));
+    } else if ((survive & count_bit) != 0u) {
+        // Survivors keep their cell, aging by a step
+        set_cell_lifetime(cell, cell_lifetime(cell) + 1u);
+        write_cell(pos, cell);
+    } else {
+        write_cell(pos, matter_cell(empty_matter));
+    }
+}
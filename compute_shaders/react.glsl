@@ -0,0 +1,47 @@
+#version 450
+
+#include "includes.glsl"
+
+/// Whether any of the 8 neighbors of pos is of given matter
+bool touches(ivec2 pos, uint matter) {
+    for (int y = -1; y <= 1; y++) {
+        for (int x = -1; x <= 1; x++) {
+            ivec2 neighbor = pos + ivec2(x, y);
+            if ((x != 0 || y != 0) && is_inside_sim_canvas(neighbor) && read_matter(neighbor) == matter) {
+                return true;
+            }
+        }
+    }
+    return false;
+}
+
+/// Matter becomes the result of its first reaction whose reagent it touches. Only the cell itself
+/// changes, the reagent reacts only if it has a reaction of its own. Reacting is the first kernel
+/// of a step, thus it also ages cells and resets their velocity, which the moves add up.
+void react(ivec2 pos) {
+    Cell cell = read_cell(pos);
+    uint lifetime = cell_lifetime(cell) + 1u;
+    MatterProperties properties = matter_properties[cell.matter];
+    for (uint i = properties.reactions_start; i < properties.reactions_start + properties.reactions_len; i++) {
+        MatterReaction reaction = matter_reactions[i];
+        if (touches(pos, reaction.reagent)) {
+            // The product starts at its own temperature, e.g. fire from burning wood is hot, and
+            // keeps the cell's color variation
+            cell.matter = reaction.product;
+            cell.temperature = matter_properties[reaction.product].temperature;
+            lifetime = 0u;
+            break;
+        }
+    }
+    set_cell_lifetime(cell, lifetime);
+    set_cell_velocity(cell, vec2(0.0));
+    write_cell(pos, cell);
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    react(pos);
+}
@@ -0,0 +1,36 @@
+#version 450
+
+#include "includes.glsl"
+
+/// Gases rise into empty cells and through denser liquids & gases
+bool rises_into(uint from, uint to) {
+    return is_gas(from) && is_fluid(to) &&
+        (is_empty(to) || matter_density(from) < matter_density(to));
+}
+
+bool wants_to_rise(ivec2 pos) {
+    return is_inside_sim_canvas(pos + UP) && rises_into(read_matter(pos), read_matter(pos + UP));
+}
+
+/// Mirror of `falls` in fall_empty.glsl
+bool rises(ivec2 pos) {
+    return wants_to_rise(pos) && (is_empty(read_matter(pos + UP)) || !wants_to_rise(pos + UP));
+}
+
+void rise_empty(ivec2 pos) {
+    Cell cell = read_cell(pos);
+    if (is_inside_sim_canvas(pos + DOWN) && rises(pos + DOWN)) {
+        cell = moved_cell(pos + DOWN, UP);
+    } else if (rises(pos)) {
+        cell = moved_cell(pos + UP, DOWN);
+    }
+    write_cell(pos, cell);
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    rise_empty(pos);
+}
@@ -0,0 +1,49 @@
+#version 450
+
+#include "includes.glsl"
+
+/// Direction (LEFT, RIGHT or NO_MOVE) in which the matter at pos slides diagonally downwards.
+/// Matter resting on something slides to an empty diagonal cell, picking one at random if both
+/// are empty.
+ivec2 slide_direction(ivec2 pos) {
+    uint matter = read_matter(pos);
+    if (!(is_powder(matter) || is_liquid(matter)) || !is_blocked(pos, DOWN)) {
+        return NO_MOVE;
+    }
+    bool left = is_empty_at(pos + DOWN_LEFT);
+    bool right = is_empty_at(pos + DOWN_RIGHT);
+    if (left && right) {
+        return random_bool(pos, RANDOM_SLIDE) ? LEFT : RIGHT;
+    }
+    return left ? LEFT : right ? RIGHT : NO_MOVE;
+}
+
+/// An empty cell may be targeted from both upper diagonals. The one sliding left (from up right)
+/// wins, and the losing cell, evaluating the same rule, stays where it is. The sliding cell swaps
+/// places with the empty cell.
+void slide_down_empty(ivec2 pos) {
+    Cell cell = read_cell(pos);
+    if (is_empty(cell.matter)) {
+        if (is_inside_sim_canvas(pos + UP_RIGHT) && slide_direction(pos + UP_RIGHT) == LEFT) {
+            cell = moved_cell(pos + UP_RIGHT, DOWN_LEFT);
+        } else if (is_inside_sim_canvas(pos + UP_LEFT) && slide_direction(pos + UP_LEFT) == RIGHT) {
+            cell = moved_cell(pos + UP_LEFT, DOWN_RIGHT);
+        }
+    } else {
+        ivec2 dir = slide_direction(pos);
+        bool accepted = dir == LEFT ||
+            (dir == RIGHT && !(is_inside_sim_canvas(pos + 2 * RIGHT) && slide_direction(pos + 2 * RIGHT) == LEFT));
+        if (accepted) {
+            cell = moved_cell(pos + DOWN + dir, -(DOWN + dir));
+        }
+    }
+    write_cell(pos, cell);
+}
+
+void main() {
+    ivec2 pos;
+    if (!get_current_sim_pos(pos)) {
+        return;
+    }
+    slide_down_empty(pos);
+}
@@ -0,0 +1,384 @@
+use bevy::math::{IVec2, UVec2};
+use strum_macros::{Display, EnumIter};
+use vulkano::buffer::BufferContents;
+
+/// Spray density unless changed in the gui
+pub const DEFAULT_SPRAY_DENSITY: f32 = 0.1;
+
+/// How drawing with the mouse covers cells
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display, EnumIter)]
+pub enum BrushTool {
+    /// Filled circle of brush radius along the mouse path
+    Round,
+    /// Filled square of brush radius along the mouse path
+    Square,
+    /// Random cells of the round brush
+    Spray,
+    /// Line of brush radius from press to release
+    Line,
+    /// Filled rectangle from press to release
+    Rectangle,
+    /// Connected region of the clicked cell's matter
+    #[strum(serialize = "Flood fill")]
+    FloodFill,
+}
+
+impl BrushTool {
+    /// Tools drawing only once the mouse is released, spanning from press to release
+    pub fn is_drag(&self) -> bool {
+        matches!(self, BrushTool::Line | BrushTool::Rectangle)
+    }
+
+    /// Shape stamped by the tool, none for flood fill
+    pub fn shape(&self) -> Option<BrushShape> {
+        match self {
+            BrushTool::Round | BrushTool::Line => Some(BrushShape::Round),
+            BrushTool::Square => Some(BrushShape::Square),
+            BrushTool::Spray => Some(BrushShape::Spray),
+            BrushTool::Rectangle => Some(BrushShape::Rectangle),
+            BrushTool::FloodFill => None,
+        }
+    }
+}
+
+/// Shapes stamped by the brush kernel, must match brush.glsl
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BrushShape {
+    /// Cells within radius of a segment
+    Round = 0,
+    /// Cells within radius of a segment along both axes
+    Square = 1,
+    /// Random cells of the round shape
+    Spray = 2,
+    /// Cells of the rectangle spanned by a segment's ends, inclusive
+    Rectangle = 3,
+}
+
+/// Brush kernel mode writing listed cells instead of stamping a shape
+pub const LISTED_CELLS: u32 = 4;
+
+/// Brush kernel mode replacing any matter, must match brush.glsl
+pub const REPLACE_ANY: u32 = u32::MAX;
+
+/// Brush kernel parameters as laid out in brush.glsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, BufferContents)]
+pub struct BrushParams {
+    /// [`BrushShape`] as u32 or [`LISTED_CELLS`]
+    pub shape: u32,
+    pub matter: u32,
+    /// Matter to replace, or [`REPLACE_ANY`]
+    pub replace_only: u32,
+    pub radius: f32,
+    pub density: f32,
+    pub seed: u32,
+    /// First cell of the dispatched region
+    pub offset: [i32; 2],
+    pub segments_len: u32,
+    /// Written by the kernel: number of changed cells
+    pub changes_len: u32,
+}
+
+/// Brush shape moved along segments from cell to cell, stamped on the gpu. Spray covers a
+/// `density` share of the round shape, chosen by `seed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrushStroke {
+    pub shape: BrushShape,
+    pub segments: Vec<[IVec2; 2]>,
+    pub radius: f32,
+    pub density: f32,
+    pub seed: u32,
+}
+
+impl BrushStroke {
+    /// Inclusive bounds of the cells the stroke may cover, clipped to the canvas. None if the
+    /// stroke lies outside the canvas.
+    pub fn bounds(&self, canvas_size: UVec2) -> Option<(IVec2, IVec2)> {
+        let reach = match self.shape {
+            BrushShape::Rectangle => 0,
+            _ => self.radius.ceil() as i32,
+        };
+        let (min, max) = self.segments.iter().fold(
+            (IVec2::splat(i32::MAX), IVec2::splat(i32::MIN)),
+            |(min, max), [a, b]| (min.min(a.min(*b)), max.max(a.max(*b))),
+        );
+        let min = (min - reach).max(IVec2::ZERO);
+        let max = (max + reach).min(canvas_size.as_ivec2() - 1);
+        (min.x <= max.x && min.y <= max.y).then_some((min, max))
+    }
+}
+
+/// Cells connected to `start` (by sides) having the same matter
+pub fn flood_fill(matter: &[u32], canvas_size: UVec2, start: IVec2) -> Vec<IVec2> {
+    let size = canvas_size.as_ivec2();
+    let inside = |pos: IVec2| pos.x >= 0 && pos.y >= 0 && pos.x < size.x && pos.y < size.y;
+    if !inside(start) {
+        return vec![];
+    }
+    let index = |pos: IVec2| (pos.y * size.x + pos.x) as usize;
+    let target = matter[index(start)];
+    let mut visited = vec![false; matter.len()];
+    visited[index(start)] = true;
+    let mut stack = vec![start];
+    let mut cells = vec![];
+    while let Some(pos) = stack.pop() {
+        cells.push(pos);
+        for dir in [IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y] {
+            let neighbor = pos + dir;
+            if inside(neighbor) && !visited[index(neighbor)] && matter[index(neighbor)] == target {
+                visited[index(neighbor)] = true;
+                stack.push(neighbor);
+            }
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec2;
+
+    use super::*;
+    use crate::{
+        headless::test_simulator, history::CellChange, matter::MatterId, rules::RuleSet,
+        utils::pcg_hash,
+    };
+
+    fn stroke(shape: BrushShape, a: IVec2, b: IVec2, radius: f32) -> BrushStroke {
+        BrushStroke {
+            shape,
+            segments: vec![[a, b]],
+            radius,
+            density: 1.0,
+            seed: 0,
+        }
+    }
+
+    #[test]
+    fn bounds_are_clipped_to_canvas() {
+        let size = UVec2::new(10, 10);
+        let round = stroke(BrushShape::Round, IVec2::new(1, 5), IVec2::new(4, 6), 2.5);
+        assert_eq!(
+            round.bounds(size),
+            Some((IVec2::new(0, 2), IVec2::new(7, 9)))
+        );
+        let rectangle = stroke(
+            BrushShape::Rectangle,
+            IVec2::new(4, 6),
+            IVec2::new(1, 5),
+            2.5,
+        );
+        assert_eq!(
+            rectangle.bounds(size),
+            Some((IVec2::new(1, 5), IVec2::new(4, 6)))
+        );
+        let outside = stroke(BrushShape::Round, IVec2::new(-5, 0), IVec2::new(-5, 9), 2.0);
+        assert_eq!(outside.bounds(size), None);
+    }
+
+    /// Distance from p to the closest point of the segment from a to b, as in brush.glsl
+    fn segment_distance(p: Vec2, a: Vec2, b: Vec2) -> f32 {
+        let ab = b - a;
+        let len2 = ab.dot(ab);
+        let t = if len2 > 0.0 {
+            ((p - a).dot(ab) / len2).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        p.distance(a + t * ab)
+    }
+
+    /// Whether the square of radius r moved from a to b covers p, as in brush.glsl
+    fn in_square_segment(p: Vec2, a: Vec2, b: Vec2, r: f32) -> bool {
+        let (mut t_min, mut t_max) = (0.0f32, 1.0f32);
+        for axis in 0..2 {
+            let d = b[axis] - a[axis];
+            let low = p[axis] - a[axis] - r;
+            let high = p[axis] - a[axis] + r;
+            if d == 0.0 {
+                if low > 0.0 || high < 0.0 {
+                    return false;
+                }
+            } else {
+                t_min = t_min.max((low / d).min(high / d));
+                t_max = t_max.min((low / d).max(high / d));
+            }
+        }
+        t_min <= t_max
+    }
+
+    /// Cpu reference of the cells brush.glsl covers with the stroke, in index order
+    fn covered(stroke: &BrushStroke, canvas_size: UVec2) -> Vec<IVec2> {
+        let Some((min, max)) = stroke.bounds(canvas_size) else {
+            return vec![];
+        };
+        let sprayed = |pos: IVec2| {
+            let hash = pcg_hash(pcg_hash(stroke.seed ^ pos.x as u32) ^ pos.y as u32);
+            (hash as f32 / u32::MAX as f32) < stroke.density
+        };
+        let covers = |pos: IVec2| {
+            stroke.segments.iter().any(|&[a, b]| match stroke.shape {
+                BrushShape::Rectangle => pos.cmpge(a.min(b)).all() && pos.cmple(a.max(b)).all(),
+                BrushShape::Square => in_square_segment(
+                    pos.as_vec2(),
+                    a.as_vec2(),
+                    b.as_vec2(),
+                    stroke.radius.floor(),
+                ),
+                BrushShape::Round | BrushShape::Spray => {
+                    (segment_distance(pos.as_vec2(), a.as_vec2(), b.as_vec2()) + 0.5).floor()
+                        <= stroke.radius
+                        && (stroke.shape != BrushShape::Spray || sprayed(pos))
+                }
+            })
+        };
+        (min.y..=max.y)
+            .flat_map(|y| (min.x..=max.x).map(move |x| IVec2::new(x, y)))
+            .filter(|&pos| covers(pos))
+            .collect()
+    }
+
+    const CANVAS_SIZE: UVec2 = UVec2::new(100, 70);
+
+    const CENTER: IVec2 = IVec2::new(50, 35);
+
+    #[test]
+    fn square_covers_more_than_round() {
+        let square = covered(
+            &stroke(BrushShape::Square, CENTER, CENTER, 3.0),
+            CANVAS_SIZE,
+        );
+        let round = covered(&stroke(BrushShape::Round, CENTER, CENTER, 3.0), CANVAS_SIZE);
+        assert_eq!(square.len(), 49);
+        assert!(round.len() < square.len());
+        assert!(round.iter().all(|cell| square.contains(cell)));
+    }
+
+    #[test]
+    fn spray_covers_share_of_round() {
+        let round = covered(
+            &stroke(BrushShape::Round, CENTER, CENTER, 20.0),
+            CANVAS_SIZE,
+        );
+        let mut spray = stroke(BrushShape::Spray, CENTER, CENTER, 20.0);
+        spray.density = 0.25;
+        let sprayed = covered(&spray, CANVAS_SIZE);
+        let share = sprayed.len() as f32 / round.len() as f32;
+        assert!((0.2..0.3).contains(&share));
+        spray.seed = 1;
+        let other_seed = covered(&spray, CANVAS_SIZE);
+        assert!(other_seed.iter().any(|cell| !sprayed.contains(cell)));
+    }
+
+    #[test]
+    fn rectangle_from_any_corners() {
+        let rectangle = stroke(
+            BrushShape::Rectangle,
+            IVec2::new(2, 0),
+            IVec2::new(0, 1),
+            5.0,
+        );
+        let cells = covered(&rectangle, CANVAS_SIZE);
+        assert_eq!(cells.len(), 6);
+        assert!(cells.contains(&IVec2::new(0, 0)) && cells.contains(&IVec2::new(2, 1)));
+    }
+
+    #[test]
+    fn moving_square_sweeps_band() {
+        // Parts outside the canvas are skipped
+        let band = stroke(
+            BrushShape::Square,
+            IVec2::new(-10, 1),
+            IVec2::new(10, 1),
+            1.0,
+        );
+        assert_eq!(covered(&band, CANVAS_SIZE).len(), 12 * 3);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_stamps_shapes() {
+        let mut simulator = test_simulator(CANVAS_SIZE, &RuleSet::falling_sand());
+        let sand = MatterId(1);
+        let mut spray = stroke(BrushShape::Spray, CENTER, CENTER, 20.0);
+        spray.density = 0.25;
+        let strokes = [
+            stroke(BrushShape::Square, CENTER, CENTER, 3.0),
+            stroke(BrushShape::Round, CENTER, CENTER, 3.0),
+            stroke(BrushShape::Round, IVec2::new(3, 60), IVec2::new(90, 2), 4.5),
+            spray,
+            stroke(
+                BrushShape::Rectangle,
+                IVec2::new(2, 0),
+                IVec2::new(0, 1),
+                5.0,
+            ),
+            stroke(
+                BrushShape::Square,
+                IVec2::new(-10, 1),
+                IVec2::new(10, 1),
+                1.0,
+            ),
+        ];
+        for stroke in &strokes {
+            let changes = simulator.draw_stroke(stroke, sand, None);
+            let mut indices: Vec<u32> = changes.iter().map(|c| c.index).collect();
+            indices.sort();
+            let expected: Vec<u32> = covered(stroke, CANVAS_SIZE)
+                .iter()
+                .map(|pos| pos.y as u32 * CANVAS_SIZE.x + pos.x as u32)
+                .collect();
+            assert_eq!(indices, expected, "{:?}", stroke.shape);
+            let undo: Vec<CellChange> = changes
+                .iter()
+                .map(|c| CellChange {
+                    index: c.index,
+                    before: c.after,
+                    after: c.before,
+                })
+                .collect();
+            simulator.apply_changes(&undo);
+        }
+        // Undoing left the canvas empty
+        assert!(simulator.matter().iter().all(|&m| m == MatterId::EMPTY.0));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_replaces_only_given_matter() {
+        let mut simulator = test_simulator(UVec2::new(4, 1), &RuleSet::falling_sand());
+        let (sand, water) = (MatterId(1), MatterId(2));
+        simulator.set_matter(&[sand.0, 0, sand.0, 0]);
+        let line = stroke(BrushShape::Round, IVec2::new(0, 0), IVec2::new(3, 0), 0.0);
+        let changes = simulator.draw_stroke(&line, water, Some(sand));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(simulator.matter(), vec![water.0, 0, water.0, 0]);
+        // Listed cells are filtered alike
+        let changes = simulator.draw_matter(&[IVec2::new(1, 0), IVec2::new(5, 0)], sand, None);
+        assert_eq!(changes, vec![CellChange {
+            index: 1,
+            before: 0,
+            after: sand.0
+        }]);
+    }
+
+    #[test]
+    fn flood_fill_stays_in_region() {
+        // Wall of 1s splits the canvas in two
+        #[rustfmt::skip]
+        let matter = [
+            0, 1, 0,
+            0, 1, 0,
+            0, 1, 0,
+        ];
+        let mut cells = flood_fill(&matter, UVec2::new(3, 3), IVec2::new(0, 1));
+        cells.sort_by_key(|cell| (cell.y, cell.x));
+        assert_eq!(cells, vec![
+            IVec2::new(0, 0),
+            IVec2::new(0, 1),
+            IVec2::new(0, 2)
+        ]);
+        assert!(flood_fill(&matter, UVec2::new(3, 3), IVec2::new(5, 5)).is_empty());
+    }
+}
@@ -1,39 +1,294 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use bevy::{
-    math::{IVec2, Vec2},
+    log::debug,
+    math::{IVec2, UVec2},
     prelude::Resource,
 };
 use vulkano::{
-    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
         allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage,
-        PrimaryAutoCommandBuffer, PrimaryCommandBufferAbstract,
+        CopyBufferInfo, PrimaryAutoCommandBuffer, PrimaryCommandBufferAbstract,
     },
     descriptor_set::{
         allocator::StandardDescriptorSetAllocator, PersistentDescriptorSet, WriteDescriptorSet,
     },
     device::{DeviceOwned, Queue},
     format::Format,
-    image::{ImageUsage, StorageImage},
+    image::{view::ImageView, ImageCreateFlags, ImageDimensions, ImageUsage, StorageImage},
     memory::allocator::{AllocationCreateInfo, MemoryUsage, StandardMemoryAllocator},
-    pipeline::{ComputePipeline, Pipeline, PipelineBindPoint},
-    sync::GpuFuture,
+    pipeline::{ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout},
+    sync::{self, GpuFuture},
 };
 use vulkano_util::renderer::DeviceImageView;
 
 use crate::{
-    utils::{create_compute_pipeline, storage_buffer_desc, storage_image_desc},
-    CANVAS_SIZE_X, CANVAS_SIZE_Y, LOCAL_SIZE_X, LOCAL_SIZE_Y, NUM_WORK_GROUPS_X, NUM_WORK_GROUPS_Y,
+    brush::{BrushParams, BrushStroke, LISTED_CELLS, REPLACE_ANY},
+    cell::Cell,
+    double_buffer::DoubleBuffer,
+    history::CellChange,
+    matter::{MatterId, MatterProperties, MatterReaction, MatterRegistry},
+    rules::{CellularRule, KernelConstants, RuleSet, StepConstants, RULE_BUFFER_BINDING},
+    utils::{create_compute_pipeline, storage_buffer_desc},
+    LOCAL_SIZE_X, LOCAL_SIZE_Y,
 };
 
-/// Creates a grid with empty matter values
+/// Creates a device local grid of [`Cell`]s, cleared to empty cells by commands recorded to
+/// `builder`
 fn empty_grid(
     allocator: &Arc<StandardMemoryAllocator>,
-    width: u32,
-    height: u32,
-) -> Subbuffer<[u32]> {
+    builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    canvas_size: UVec2,
+) -> Subbuffer<[Cell]> {
+    let len = (canvas_size.x * canvas_size.y) as usize;
+    // A cell is several words, thus it's uploaded instead of filling the buffer with one word
+    let staging = upload_buffer(allocator, BufferUsage::TRANSFER_SRC, vec![Cell::EMPTY; len]);
+    let grid = Buffer::new_slice(
+        allocator,
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER
+                | BufferUsage::TRANSFER_SRC
+                | BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::DeviceOnly,
+            ..Default::default()
+        },
+        len as u64,
+    )
+    .unwrap();
+    builder
+        .copy_buffer(CopyBufferInfo::buffers(staging, grid.clone()))
+        .unwrap();
+    grid
+}
+
+/// Creates a device local read only storage buffer, uploading given data through a staging
+/// buffer by commands recorded to `builder`
+fn device_local_buffer<T: BufferContents>(
+    allocator: &Arc<StandardMemoryAllocator>,
+    builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    data: Vec<T>,
+) -> Subbuffer<[T]> {
+    let staging = upload_buffer(allocator, BufferUsage::TRANSFER_SRC, data);
+    let buffer = Buffer::new_slice(
+        allocator,
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::DeviceOnly,
+            ..Default::default()
+        },
+        staging.len(),
+    )
+    .unwrap();
+    builder
+        .copy_buffer(CopyBufferInfo::buffers(staging, buffer.clone()))
+        .unwrap();
+    buffer
+}
+
+/// Creates a host visible buffer written once by the cpu, e.g. for staging uploads
+fn upload_buffer<T: BufferContents>(
+    allocator: &Arc<StandardMemoryAllocator>,
+    usage: BufferUsage,
+    data: Vec<T>,
+) -> Subbuffer<[T]> {
     Buffer::from_iter(
+        allocator,
+        BufferCreateInfo {
+            usage,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::Upload,
+            ..Default::default()
+        },
+        data,
+    )
+    .unwrap()
+}
+
+/// Creates a host visible buffer of `len` elements for the gpu to write and the cpu to read back
+fn download_buffer<T: BufferContents>(
+    allocator: &Arc<StandardMemoryAllocator>,
+    usage: BufferUsage,
+    len: u64,
+) -> Subbuffer<[T]> {
+    Buffer::new_slice(
+        allocator,
+        BufferCreateInfo {
+            usage,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            usage: MemoryUsage::Download,
+            ..Default::default()
+        },
+        len,
+    )
+    .unwrap()
+}
+
+/// Buffers & image of includes.glsl which every rule kernel binds besides the matter buffers
+struct KernelBindings {
+    image: DeviceImageView,
+    matter_properties: Subbuffer<[MatterProperties]>,
+    matter_reactions: Subbuffer<[MatterReaction]>,
+}
+
+/// Pipeline of a rule, whether it swaps matter, and its descriptor sets for each matter buffer
+/// parity
+#[derive(Clone)]
+struct RulePipeline {
+    pipeline: Arc<ComputePipeline>,
+    swaps_matter: bool,
+    descriptor_sets: [Arc<PersistentDescriptorSet>; 2],
+}
+
+impl RulePipeline {
+    /// Rule kernels bind the same buffers, only the roles of the matter buffers alternate, and
+    /// their own buffer if they have one
+    fn new(
+        rule: &dyn CellularRule,
+        compute_queue: Arc<Queue>,
+        canvas_size: UVec2,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: &StandardDescriptorSetAllocator,
+        matter: &DoubleBuffer<Subbuffer<[Cell]>>,
+        bindings: &KernelBindings,
+    ) -> RulePipeline {
+        debug!("Creating pipeline of rule {}", rule.name());
+        let pipeline = rule.create_pipeline(compute_queue, canvas_size);
+        let rule_buffer = rule
+            .rule_buffer()
+            .map(|data| upload_buffer(memory_allocator, BufferUsage::STORAGE_BUFFER, data));
+        let desc_layout = pipeline.layout().set_layouts().first().unwrap();
+        let descriptor_sets = [0, 1].map(|parity| {
+            let (current, next) = matter.at_parity(parity);
+            let mut writes = vec![
+                WriteDescriptorSet::buffer(0, current.clone()),
+                WriteDescriptorSet::buffer(1, next.clone()),
+                WriteDescriptorSet::image_view(2, bindings.image.clone()),
+                WriteDescriptorSet::buffer(3, bindings.matter_properties.clone()),
+                WriteDescriptorSet::buffer(4, bindings.matter_reactions.clone()),
+            ];
+            writes.extend(
+                rule_buffer
+                    .clone()
+                    .map(|buffer| WriteDescriptorSet::buffer(RULE_BUFFER_BINDING, buffer)),
+            );
+            PersistentDescriptorSet::new(descriptor_set_allocator, desc_layout.clone(), writes)
+                .unwrap()
+        });
+        RulePipeline {
+            pipeline,
+            swaps_matter: rule.swaps_matter(),
+            descriptor_sets,
+        }
+    }
+}
+
+/// Pipelines of the step's rules and the color pipeline
+fn rule_pipelines(
+    rules: &RuleSet,
+    compute_queue: Arc<Queue>,
+    canvas_size: UVec2,
+    memory_allocator: &Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: &StandardDescriptorSetAllocator,
+    matter: &DoubleBuffer<Subbuffer<[Cell]>>,
+    bindings: &KernelBindings,
+) -> (Vec<RulePipeline>, RulePipeline) {
+    let pipeline = |rule: &dyn CellularRule| {
+        RulePipeline::new(
+            rule,
+            compute_queue.clone(),
+            canvas_size,
+            memory_allocator,
+            descriptor_set_allocator,
+            matter,
+            bindings,
+        )
+    };
+    let step_pipelines = rules
+        .step
+        .iter()
+        .map(|rule| pipeline(rule.as_ref()))
+        .collect();
+    (step_pipelines, pipeline(rules.color.as_ref()))
+}
+
+/// Submissions chained without waiting for them. Bounds the work queued ahead of the cpu, and the
+/// length of the future chain, when nothing renders, e.g. when running headless.
+const MAX_PENDING_SUBMISSIONS: u32 = 256;
+
+/// Compute work submitted but not yet waited for
+struct PendingWork {
+    future: Box<dyn GpuFuture + Send + Sync>,
+    submissions: u32,
+}
+
+/// Segments and changes the brush buffers hold at first, before growing for larger strokes
+const INITIAL_BRUSH_CAPACITY: u64 = 1024;
+
+/// Host visible buffers of the brush kernel, reused by every drawing. Drawing waits for the
+/// kernel, so the cpu may rewrite them on the next call.
+struct BrushBuffers {
+    params: Subbuffer<BrushParams>,
+    segments: Subbuffer<[[i32; 4]]>,
+    changes: Subbuffer<[CellChange]>,
+}
+
+impl BrushBuffers {
+    fn new(allocator: &Arc<StandardMemoryAllocator>) -> BrushBuffers {
+        let params = Buffer::new_sized(
+            allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                usage: MemoryUsage::Download,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        BrushBuffers {
+            params,
+            segments: segments_buffer(allocator, INITIAL_BRUSH_CAPACITY),
+            changes: download_buffer(
+                allocator,
+                BufferUsage::STORAGE_BUFFER,
+                INITIAL_BRUSH_CAPACITY,
+            ),
+        }
+    }
+
+    /// Grow the buffers, doubling their capacity, until they hold the given segments & changes
+    fn reserve(
+        &mut self,
+        allocator: &Arc<StandardMemoryAllocator>,
+        segments_len: u64,
+        max_changes: u64,
+    ) {
+        if segments_len > self.segments.len() {
+            let capacity = segments_len.next_power_of_two();
+            self.segments = segments_buffer(allocator, capacity);
+        }
+        if max_changes > self.changes.len() {
+            let capacity = max_changes.next_power_of_two();
+            self.changes = download_buffer(allocator, BufferUsage::STORAGE_BUFFER, capacity);
+        }
+    }
+}
+
+/// Creates a host visible buffer of brush segments for the cpu to write
+fn segments_buffer(allocator: &Arc<StandardMemoryAllocator>, len: u64) -> Subbuffer<[[i32; 4]]> {
+    Buffer::new_slice(
         allocator,
         BufferCreateInfo {
             usage: BufferUsage::STORAGE_BUFFER,
@@ -43,7 +298,7 @@ fn empty_grid(
             usage: MemoryUsage::Upload,
             ..Default::default()
         },
-        vec![0; (width * height) as usize],
+        len,
     )
     .unwrap()
 }
@@ -51,161 +306,575 @@ fn empty_grid(
 /// Cellular automata simulation pipeline
 #[derive(Resource)]
 pub struct CASimulator {
+    memory_allocator: Arc<StandardMemoryAllocator>,
     compute_queue: Arc<Queue>,
-    color_pipeline: Arc<ComputePipeline>,
-    matter_in: Subbuffer<[u32]>,
-    matter_out: Subbuffer<[u32]>,
-    image: DeviceImageView,
+    canvas_size: UVec2,
+    seed: u32,
+    steps: u64,
+    /// Pipelines of the step's rules in dispatch order
+    step_pipelines: Vec<RulePipeline>,
+    color_pipeline: RulePipeline,
+    brush_pipeline: Arc<ComputePipeline>,
+    brush_buffers: BrushBuffers,
+    matter: DoubleBuffer<Subbuffer<[Cell]>>,
+    bindings: KernelBindings,
+    /// Host copy of the matter properties, for creating cells
+    properties: Vec<MatterProperties>,
+    pending: Mutex<Option<PendingWork>>,
     command_buffer_allocator: StandardCommandBufferAllocator,
     descriptor_set_allocator: StandardDescriptorSetAllocator,
 }
 
 impl CASimulator {
-    /// Create new simulator pipeline for a compute queue. Ensure that canvas sizes are divisible by
-    /// kernel sizes so no pixel remains unsimulated.
-    pub fn new(allocator: &Arc<StandardMemoryAllocator>, compute_queue: Arc<Queue>) -> CASimulator {
-        // In order to not miss any pixels, the following must be true
-        assert_eq!(CANVAS_SIZE_X % LOCAL_SIZE_X, 0);
-        assert_eq!(CANVAS_SIZE_Y % LOCAL_SIZE_Y, 0);
-        let matter_in = empty_grid(allocator, CANVAS_SIZE_X, CANVAS_SIZE_Y);
-        let matter_out = empty_grid(allocator, CANVAS_SIZE_X, CANVAS_SIZE_Y);
-
-        let spec_const = color_cs::SpecializationConstants {
-            canvas_size_x: CANVAS_SIZE_X as i32,
-            canvas_size_y: CANVAS_SIZE_Y as i32,
-            empty_matter: 0,
-            constant_3: LOCAL_SIZE_X,
-            constant_4: LOCAL_SIZE_Y,
-        };
+    /// Create new simulator pipeline for a compute queue, given matters, rules and canvas size.
+    /// Canvas size need not be divisible by kernel size, kernels skip invocations outside the
+    /// canvas. Random choices of the kernels depend only on the seed, step and cell, so a seed
+    /// with an initial grid always reproduces the same run. If the color image is rendered on a
+    /// queue of another family, `render_queue_family` shares the image with it.
+    pub fn new(
+        allocator: &Arc<StandardMemoryAllocator>,
+        compute_queue: Arc<Queue>,
+        render_queue_family: Option<u32>,
+        matter_registry: &MatterRegistry,
+        rules: &RuleSet,
+        canvas_size: UVec2,
+        seed: u32,
+    ) -> CASimulator {
+        // Buffers the kernels use live in device local memory. Their initial data is uploaded
+        // once, before the simulator is used.
+        let command_buffer_allocator =
+            StandardCommandBufferAllocator::new(allocator.device().clone(), Default::default());
+        let mut uploads = AutoCommandBufferBuilder::primary(
+            &command_buffer_allocator,
+            compute_queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        let matter = DoubleBuffer::new(
+            empty_grid(allocator, &mut uploads, canvas_size),
+            empty_grid(allocator, &mut uploads, canvas_size),
+        );
+
+        let properties = matter_registry.properties();
+        let matter_properties = device_local_buffer(allocator, &mut uploads, properties.clone());
+        // Buffers can't be empty. The placeholder is never read, because no matter has reactions.
+        let mut reactions = matter_registry.reactions();
+        if reactions.is_empty() {
+            reactions.push(MatterReaction {
+                reagent: MatterId::EMPTY.0,
+                product: MatterId::EMPTY.0,
+            });
+        }
+        let matter_reactions = device_local_buffer(allocator, &mut uploads, reactions);
+        uploads
+            .build()
+            .unwrap()
+            .execute(compute_queue.clone())
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
 
         // Create pipelines
-        let color_pipeline = {
-            let color_shader = color_cs::load(compute_queue.device().clone()).unwrap();
-            // This must match the shader & inputs in dispatch
-            let descriptor_layout = [
+        let device = compute_queue.device().clone();
+        // The brush draws to the current matter in place, given its stroke and a buffer for
+        // recording changes. Drawn cells take their matter's temperature.
+        let brush_pipeline = create_compute_pipeline(
+            compute_queue.clone(),
+            brush_cs::load(device.clone())
+                .unwrap()
+                .entry_point("main")
+                .unwrap(),
+            vec![
                 (0, storage_buffer_desc()),
-                (1, storage_buffer_desc()),
-                (2, storage_image_desc()),
                 (3, storage_buffer_desc()),
-            ];
-            create_compute_pipeline(
-                compute_queue.clone(),
-                color_shader.entry_point("main").unwrap(),
-                descriptor_layout.to_vec(),
-                &spec_const,
-            )
-        };
+                (5, storage_buffer_desc()),
+                (6, storage_buffer_desc()),
+                (7, storage_buffer_desc()),
+            ],
+            &KernelConstants::new(canvas_size),
+        );
         // Create color image
-        let image = StorageImage::general_purpose_image_view(
+        let mut queue_families = vec![compute_queue.queue_family_index()];
+        queue_families.extend(render_queue_family.filter(|&family| family != queue_families[0]));
+        let image = StorageImage::with_usage(
             allocator,
-            compute_queue.clone(),
-            [CANVAS_SIZE_X, CANVAS_SIZE_Y],
+            ImageDimensions::Dim2d {
+                width: canvas_size.x,
+                height: canvas_size.y,
+                array_layers: 1,
+            },
             Format::R8G8B8A8_UNORM,
             ImageUsage::SAMPLED | ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
+            ImageCreateFlags::empty(),
+            queue_families,
         )
         .unwrap();
+        let image = ImageView::new_default(image).unwrap();
+
+        let bindings = KernelBindings {
+            image,
+            matter_properties,
+            matter_reactions,
+        };
+        let descriptor_set_allocator = StandardDescriptorSetAllocator::new(device);
+        let (step_pipelines, color_pipeline) = rule_pipelines(
+            rules,
+            compute_queue.clone(),
+            canvas_size,
+            allocator,
+            &descriptor_set_allocator,
+            &matter,
+            &bindings,
+        );
         CASimulator {
+            memory_allocator: allocator.clone(),
             compute_queue,
+            canvas_size,
+            seed,
+            steps: 0,
+            step_pipelines,
             color_pipeline,
-            matter_in,
-            matter_out,
-            image,
-            command_buffer_allocator: StandardCommandBufferAllocator::new(
-                allocator.device().clone(),
-                Default::default(),
-            ),
-            descriptor_set_allocator: StandardDescriptorSetAllocator::new(
-                allocator.device().clone(),
-            ),
+            brush_pipeline,
+            brush_buffers: BrushBuffers::new(allocator),
+            matter,
+            bindings,
+            properties,
+            pending: Mutex::new(None),
+            command_buffer_allocator,
+            descriptor_set_allocator,
         }
     }
 
     /// Get canvas image for rendering
     pub fn color_image(&self) -> DeviceImageView {
-        self.image.clone()
+        self.bindings.image.clone()
+    }
+
+    /// Take the compute work submitted since the last call. Rendering must wait for it before
+    /// sampling the color image.
+    pub fn take_future(&self) -> Option<Box<dyn GpuFuture + Send + Sync>> {
+        self.pending
+            .lock()
+            .unwrap()
+            .take()
+            .map(|pending| pending.future)
+    }
+
+    /// Block until submitted compute work has finished
+    pub fn wait(&self) {
+        if let Some(pending) = self.pending.lock().unwrap().take() {
+            pending
+                .future
+                .then_signal_fence_and_flush()
+                .unwrap()
+                .wait(None)
+                .unwrap();
+        }
+    }
+
+    /// Copy of the current grid, read back through a staging buffer
+    pub fn cells(&self) -> Vec<Cell> {
+        self.read_cells(self.matter.current().clone())
+    }
+
+    /// Copy of the current matter grid
+    pub fn matter(&self) -> Vec<u32> {
+        self.cells().into_iter().map(|cell| cell.matter).collect()
+    }
+
+    /// Current state of a cell, None outside the canvas
+    pub fn cell(&self, pos: IVec2) -> Option<Cell> {
+        if !self.is_inside(pos) {
+            return None;
+        }
+        let index = self.index(pos) as u64;
+        let cell = self.matter.current().clone().slice(index..index + 1);
+        self.read_cells(cell).first().copied()
+    }
+
+    /// Read cells back through a staging buffer
+    fn read_cells(&self, cells: Subbuffer<[Cell]>) -> Vec<Cell> {
+        let staging = download_buffer(
+            &self.memory_allocator,
+            BufferUsage::TRANSFER_DST,
+            cells.len(),
+        );
+        let mut command_buffer_builder = self.command_buffer_builder();
+        command_buffer_builder
+            .copy_buffer(CopyBufferInfo::buffers(cells, staging.clone()))
+            .unwrap();
+        self.submit(command_buffer_builder);
+        self.wait();
+        staging.read().map(|cells| cells.to_vec()).unwrap()
+    }
+
+    /// Replace the current matter grid with new cells of the given matter
+    pub fn set_matter(&mut self, matter: &[u32]) {
+        let cells: Vec<Cell> = matter
+            .iter()
+            .map(|&m| Cell::of_matter(MatterId(m), &self.properties))
+            .collect();
+        self.replace_cells(&cells);
+    }
+
+    /// Replace the current grid with given cells, uploading them through a staging buffer
+    pub fn replace_cells(&mut self, cells: &[Cell]) {
+        let staging = upload_buffer(
+            &self.memory_allocator,
+            BufferUsage::TRANSFER_SRC,
+            cells.to_vec(),
+        );
+        let mut command_buffer_builder = self.command_buffer_builder();
+        command_buffer_builder
+            .copy_buffer(CopyBufferInfo::buffers(
+                staging,
+                self.matter.current().clone(),
+            ))
+            .unwrap();
+        self.submit(command_buffer_builder);
+    }
+
+    /// Start the states of continuous rules over from the cells' matter, keeping the rest of the
+    /// cells, e.g. when switching to Lenia
+    pub fn reset_states(&mut self) {
+        let cells: Vec<Cell> = self
+            .cells()
+            .into_iter()
+            .map(|cell| Cell {
+                state: Cell::new(MatterId(cell.matter)).state,
+                ..cell
+            })
+            .collect();
+        self.replace_cells(&cells);
+    }
+
+    /// Seed of the kernels' random numbers
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// Set the seed, e.g. when loading a saved canvas
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    /// Number of steps simulated since the canvas was created or loaded
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Set the step counter, e.g. when loading a saved canvas
+    pub fn set_steps(&mut self, steps: u64) {
+        self.steps = steps;
+    }
+
+    /// Replace the rules simulated, keeping the canvas. The next step runs the new rules.
+    pub fn set_rules(&mut self, rules: &RuleSet) {
+        (self.step_pipelines, self.color_pipeline) = rule_pipelines(
+            rules,
+            self.compute_queue.clone(),
+            self.canvas_size,
+            &self.memory_allocator,
+            &self.descriptor_set_allocator,
+            &self.matter,
+            &self.bindings,
+        );
+    }
+
+    /// Size of the simulated canvas in pixels
+    pub fn canvas_size(&self) -> UVec2 {
+        self.canvas_size
     }
 
     /// Are we within simulation bounds?
     fn is_inside(&self, pos: IVec2) -> bool {
-        pos.x >= 0 && pos.x < CANVAS_SIZE_X as i32 && pos.y >= 0 && pos.y < CANVAS_SIZE_Y as i32
+        pos.x >= 0
+            && pos.x < self.canvas_size.x as i32
+            && pos.y >= 0
+            && pos.y < self.canvas_size.y as i32
     }
 
     /// Index to access our one dimensional grid with two dimensional position
     fn index(&self, pos: IVec2) -> usize {
-        (pos.y * CANVAS_SIZE_Y as i32 + pos.x) as usize
+        (pos.y * self.canvas_size.x as i32 + pos.x) as usize
     }
 
-    /// Draw matter line with given radius
-    pub fn draw_matter(&mut self, line: &[IVec2], radius: f32, matter: u32) {
-        let mut matter_in = self.matter_in.write().unwrap();
-        for &pos in line.iter() {
-            if !self.is_inside(pos) {
-                continue;
-            }
+    /// Stamp a brush stroke with matter, skipping, if `replace_only` is given, cells of other
+    /// matter. Drawing goes to the current matter, which is what the next step reads. Only the
+    /// stroke's bounds are dispatched. Returns the cells that changed.
+    pub fn draw_stroke(
+        &mut self,
+        stroke: &BrushStroke,
+        matter: MatterId,
+        replace_only: Option<MatterId>,
+    ) -> Vec<CellChange> {
+        let (min, max) = match stroke.bounds(self.canvas_size) {
+            Some(bounds) => bounds,
+            None => return vec![],
+        };
+        let region = (max - min + 1).as_uvec2();
+        let params = BrushParams {
+            shape: stroke.shape as u32,
+            matter: matter.0,
+            replace_only: replace_only.map_or(REPLACE_ANY, |only| only.0),
+            radius: stroke.radius,
+            density: stroke.density,
+            seed: stroke.seed,
+            offset: min.to_array(),
+            segments_len: stroke.segments.len() as u32,
+            changes_len: 0,
+        };
+        let segments = stroke
+            .segments
+            .iter()
+            .map(|[a, b]| [a.x, a.y, b.x, b.y])
+            .collect();
+        let num_work_groups = [
+            region.x.div_ceil(LOCAL_SIZE_X),
+            region.y.div_ceil(LOCAL_SIZE_Y),
+            1,
+        ];
+        self.draw(params, segments, num_work_groups, region.x * region.y)
+    }
 
-            let (y1, y2) = (pos.y - radius as i32, pos.y + radius as i32);
-            let (x1, x2) = (pos.x - radius as i32, pos.x + radius as i32);
-            for y in y1..=y2 {
-                for x in x1..=x2 {
-                    let world_pos = Vec2::new(x as f32, y as f32);
-                    if world_pos
-                        .distance(Vec2::new(pos.x as f32, pos.y as f32))
-                        .round()
-                        <= radius
-                        && self.is_inside([x, y].into())
-                    {
-                        // Draw
-                        matter_in[self.index([x, y].into())] = matter;
-                    }
-                }
-            }
+    /// Draw matter to cells, skipping cells outside the canvas and, if `replace_only` is given,
+    /// cells of other matter. Returns the cells that changed.
+    pub fn draw_matter(
+        &mut self,
+        cells: &[IVec2],
+        matter: MatterId,
+        replace_only: Option<MatterId>,
+    ) -> Vec<CellChange> {
+        let replace_only = replace_only.map_or(REPLACE_ANY, |only| only.0);
+        let cells = cells
+            .iter()
+            .filter(|&&pos| self.is_inside(pos))
+            .map(|&pos| {
+                [
+                    self.index(pos) as i32,
+                    matter.0 as i32,
+                    replace_only as i32,
+                    0,
+                ]
+            })
+            .collect();
+        self.draw_listed_cells(cells)
+    }
+
+    /// Apply changes, e.g. to undo drawing: cells still of matter `before` become `after`, cells
+    /// the simulation changed since are left as they are
+    pub fn apply_changes(&mut self, changes: &[CellChange]) {
+        let cells = changes
+            .iter()
+            .map(|change| {
+                [
+                    change.index as i32,
+                    change.after as i32,
+                    change.before as i32,
+                    0,
+                ]
+            })
+            .collect();
+        self.draw_listed_cells(cells);
+    }
+
+    /// Draw cells listed as (index, matter, matter to replace or [`REPLACE_ANY`])
+    fn draw_listed_cells(&mut self, cells: Vec<[i32; 4]>) -> Vec<CellChange> {
+        if cells.is_empty() {
+            return vec![];
         }
+        let len = cells.len() as u32;
+        let params = BrushParams {
+            shape: LISTED_CELLS,
+            // Matter and matter to replace are given per cell
+            matter: MatterId::EMPTY.0,
+            replace_only: REPLACE_ANY,
+            radius: 0.0,
+            density: 0.0,
+            seed: 0,
+            offset: [0, 0],
+            segments_len: len,
+            changes_len: 0,
+        };
+        let num_work_groups = [len.div_ceil(LOCAL_SIZE_X * LOCAL_SIZE_Y), 1, 1];
+        self.draw(params, cells, num_work_groups, len)
+    }
+
+    /// Run the brush kernel and wait for the cells it changed, at most `max_changes`
+    fn draw(
+        &mut self,
+        params: BrushParams,
+        segments: Vec<[i32; 4]>,
+        num_work_groups: [u32; 3],
+        max_changes: u32,
+    ) -> Vec<CellChange> {
+        self.brush_buffers.reserve(
+            &self.memory_allocator,
+            segments.len() as u64,
+            max_changes as u64,
+        );
+        let BrushBuffers {
+            params: params_buffer,
+            segments: segments_buffer,
+            changes,
+        } = &self.brush_buffers;
+        *params_buffer.write().unwrap() = params;
+        segments_buffer.write().unwrap()[..segments.len()].copy_from_slice(&segments);
+
+        let mut command_buffer_builder = self.command_buffer_builder();
+        let pipeline_layout = self.brush_pipeline.layout();
+        let desc_layout = pipeline_layout.set_layouts().first().unwrap();
+        let set =
+            PersistentDescriptorSet::new(&self.descriptor_set_allocator, desc_layout.clone(), [
+                WriteDescriptorSet::buffer(0, self.matter.current().clone()),
+                WriteDescriptorSet::buffer(3, self.bindings.matter_properties.clone()),
+                WriteDescriptorSet::buffer(5, params_buffer.clone()),
+                WriteDescriptorSet::buffer(6, segments_buffer.clone()),
+                WriteDescriptorSet::buffer(7, changes.clone()),
+            ])
+            .unwrap();
+        command_buffer_builder
+            .bind_pipeline_compute(self.brush_pipeline.clone())
+            .bind_descriptor_sets(PipelineBindPoint::Compute, pipeline_layout.clone(), 0, set);
+        self.push_constants(&mut command_buffer_builder, pipeline_layout);
+        command_buffer_builder.dispatch(num_work_groups).unwrap();
+        self.submit(command_buffer_builder);
+        self.wait();
+
+        let changes_len = params_buffer.read().unwrap().changes_len as usize;
+        changes
+            .read()
+            .map(|changes| changes[..changes_len].to_vec())
+            .unwrap()
     }
 
     /// Step simulation
     pub fn step(&mut self) {
-        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        self.step_many(1);
+    }
+
+    /// Step simulation `count` times in one submission, which saves cpu overhead when running
+    /// many steps per frame. The command buffer orders the kernels with pipeline barriers. It's
+    /// recorded anew for each submission, because the step counter seeding random choices is
+    /// pushed per step.
+    pub fn step_many(&mut self, count: u32) {
+        if count == 0 {
+            return;
+        }
+        let mut command_buffer_builder = self.command_buffer_builder();
+        for _ in 0..count {
+            // Run the rules in order. A kernel writing matter reads the current matter and writes
+            // the next, after which they are swapped so that the next kernel sees the latest state.
+            for rule in self.step_pipelines.clone() {
+                self.dispatch(&mut command_buffer_builder, &rule);
+            }
+            self.steps += 1;
+        }
+
+        // Finally color the image, once for the last step
+        let color_pipeline = self.color_pipeline.clone();
+        self.dispatch(&mut command_buffer_builder, &color_pipeline);
+
+        self.submit(command_buffer_builder);
+    }
+
+    /// Color the image from the current matter without stepping, e.g. to show drawing while
+    /// paused
+    pub fn update_image(&mut self) {
+        let mut command_buffer_builder = self.command_buffer_builder();
+        let color_pipeline = self.color_pipeline.clone();
+        self.dispatch(&mut command_buffer_builder, &color_pipeline);
+        self.submit(command_buffer_builder);
+    }
+
+    fn command_buffer_builder(&self) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer> {
+        AutoCommandBufferBuilder::primary(
             &self.command_buffer_allocator,
             self.compute_queue.queue_family_index(),
             CommandBufferUsage::OneTimeSubmit,
         )
-        .unwrap();
-
-        // Finally color the image
-        self.dispatch(&mut command_buffer_builder, self.color_pipeline.clone());
+        .unwrap()
+    }
 
-        // Finish
-        let command_buffer = command_buffer_builder.build().unwrap();
-        let finished = command_buffer.execute(self.compute_queue.clone()).unwrap();
-        let _fut = finished.then_signal_fence_and_flush().unwrap();
+    /// Execute commands after previously submitted work, without waiting for them. A semaphore
+    /// orders them after that work, on this queue or the one rendering waits on.
+    fn submit(&self, builder: AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        let command_buffer = builder.build().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+        if pending
+            .as_ref()
+            .is_some_and(|pending| pending.submissions >= MAX_PENDING_SUBMISSIONS)
+        {
+            drop(pending);
+            self.wait();
+            pending = self.pending.lock().unwrap();
+        }
+        let (mut before, submissions) = match pending.take() {
+            Some(pending) => (pending.future, pending.submissions),
+            None => (
+                sync::now(self.compute_queue.device().clone()).boxed_send_sync(),
+                0,
+            ),
+        };
+        before.cleanup_finished();
+        let future = before
+            .then_execute(self.compute_queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_semaphore_and_flush()
+            .unwrap()
+            .boxed_send_sync();
+        *pending = Some(PendingWork {
+            future,
+            submissions: submissions + 1,
+        });
     }
 
-    /// Append a pipeline dispatch to our command buffer
+    /// Append a rule's dispatch to our command buffer. If the rule swaps matter, matter buffers
+    /// are swapped after the dispatch so its output becomes the input of the next dispatch.
     fn dispatch(
         &mut self,
         builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
-        pipeline: Arc<ComputePipeline>,
+        rule: &RulePipeline,
     ) {
-        let pipeline_layout = pipeline.layout();
-        let desc_layout = pipeline_layout.set_layouts().get(0).unwrap();
-        let set =
-            PersistentDescriptorSet::new(&self.descriptor_set_allocator, desc_layout.clone(), [
-                WriteDescriptorSet::buffer(0, self.matter_in.clone()),
-                WriteDescriptorSet::buffer(1, self.matter_out.clone()),
-                WriteDescriptorSet::image_view(2, self.image.clone()),
-            ])
-            .unwrap();
+        // Round up so that the whole canvas is covered
+        let num_work_groups = [
+            self.canvas_size.x.div_ceil(LOCAL_SIZE_X),
+            self.canvas_size.y.div_ceil(LOCAL_SIZE_Y),
+            1,
+        ];
+        let pipeline_layout = rule.pipeline.layout();
+        let set = rule.descriptor_sets[self.matter.parity()].clone();
         builder
-            .bind_pipeline_compute(pipeline.clone())
-            .bind_descriptor_sets(PipelineBindPoint::Compute, pipeline_layout.clone(), 0, set)
-            .dispatch([NUM_WORK_GROUPS_X, NUM_WORK_GROUPS_Y, 1])
-            .unwrap();
+            .bind_pipeline_compute(rule.pipeline.clone())
+            .bind_descriptor_sets(PipelineBindPoint::Compute, pipeline_layout.clone(), 0, set);
+        self.push_constants(builder, pipeline_layout);
+        builder.dispatch(num_work_groups).unwrap();
+        if rule.swaps_matter {
+            self.matter.swap();
+        }
+    }
+
+    /// Push the seed & step to kernels with random choices. Kernels without them have no push
+    /// constants.
+    fn push_constants(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        pipeline_layout: &Arc<PipelineLayout>,
+    ) {
+        if !pipeline_layout.push_constant_ranges().is_empty() {
+            builder.push_constants(pipeline_layout.clone(), 0, StepConstants {
+                seed: self.seed,
+                steps: self.steps as u32,
+            });
+        }
     }
 }
 
-mod color_cs {
+mod brush_cs {
     vulkano_shaders::shader! {
         ty: "compute",
-        path: "compute_shaders/color.glsl"
+        path: "compute_shaders/brush.glsl"
     }
 }
@@ -0,0 +1,141 @@
+use bevy::math::Vec2;
+
+use crate::matter::{MatterId, MatterProperties};
+
+/// Temperature of new cells in degrees Celsius. Must match cell.glsl.
+pub const AMBIENT_TEMPERATURE: f32 = 20.0;
+
+/// Lifetime saturates at this many steps. Must match cell.glsl.
+pub const MAX_LIFETIME: u32 = 0xffff;
+
+/// State of a grid cell, generated from cell.glsl which documents its fields. Besides its
+/// matter a cell has a temperature, the velocity it moved with during the last step, the steps
+/// since its matter appeared, a random shade of its matter's color and a state of continuous
+/// rules.
+pub use crate::rules::color_cs::Cell;
+
+impl Cell {
+    pub const EMPTY: Cell = Cell::new(MatterId::EMPTY);
+
+    /// New cell of matter at rest, as the kernels create them. Its state is full for any matter
+    /// but the empty one.
+    pub const fn new(matter: MatterId) -> Cell {
+        Cell {
+            matter: matter.0,
+            temperature: AMBIENT_TEMPERATURE,
+            velocity: 0,
+            lifetime_variation: 0,
+            state: if matter.0 == MatterId::EMPTY.0 {
+                0.0
+            } else {
+                1.0
+            },
+        }
+    }
+
+    /// New cell of matter at the matter's initial temperature, like `matter_cell` in
+    /// includes.glsl
+    pub fn of_matter(matter: MatterId, properties: &[MatterProperties]) -> Cell {
+        Cell {
+            temperature: properties[matter.0 as usize].temperature,
+            ..Cell::new(matter)
+        }
+    }
+
+    /// Cells moved during the last step
+    pub fn velocity(&self) -> Vec2 {
+        let x = self.velocity as u16 as i16;
+        let y = (self.velocity >> 16) as u16 as i16;
+        Vec2::new(x as f32, y as f32) / 256.0
+    }
+
+    /// Set velocity, rounded to 8.8 fixed point like `set_cell_velocity` in cell.glsl
+    pub fn set_velocity(&mut self, velocity: Vec2) {
+        let fixed_point = (velocity * 256.0)
+            .round()
+            .clamp(Vec2::splat(-32768.0), Vec2::splat(32767.0));
+        self.velocity =
+            (fixed_point.x as i32 as u32 & 0xffff) | ((fixed_point.y as i32 as u32) << 16);
+    }
+
+    /// Steps since the cell's matter appeared, up to [`MAX_LIFETIME`]
+    pub fn lifetime(&self) -> u32 {
+        self.lifetime_variation & MAX_LIFETIME
+    }
+
+    pub fn set_lifetime(&mut self, lifetime: u32) {
+        self.lifetime_variation =
+            (self.lifetime_variation & !MAX_LIFETIME) | lifetime.min(MAX_LIFETIME);
+    }
+
+    /// Shade of the matter's color, -128 - 127
+    pub fn color_variation(&self) -> i32 {
+        self.lifetime_variation as i32 >> 24
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::{IVec2, UVec2};
+
+    use super::*;
+    use crate::{
+        headless::test_simulator,
+        matter::{MatterRegistry, MATTER_DEFINITIONS_PATH},
+        rules::RuleSet,
+    };
+
+    #[test]
+    fn unpacks_fields() {
+        let cell = Cell {
+            velocity: 0xff00_0180,
+            lifetime_variation: 0x9c00_0005,
+            ..Cell::EMPTY
+        };
+        assert_eq!(cell.velocity(), Vec2::new(1.5, -1.0));
+        assert_eq!(cell.lifetime(), 5);
+        assert_eq!(cell.color_variation(), -100);
+        assert_eq!(Cell::EMPTY.velocity(), Vec2::ZERO);
+        let mut moved = cell;
+        moved.set_velocity(Vec2::new(-0.5, 2.0));
+        moved.set_lifetime(MAX_LIFETIME + 1);
+        assert_eq!(moved.velocity(), Vec2::new(-0.5, 2.0));
+        assert_eq!(moved.lifetime(), MAX_LIFETIME);
+        assert_eq!(moved.color_variation(), -100);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_cells_track_motion() {
+        let mut simulator = test_simulator(UVec2::new(1, 4), &RuleSet::falling_sand());
+        // Sand at the top of a column falls a cell per step
+        let sand = MatterId(1);
+        simulator.set_matter(&[0, 0, 0, sand.0]);
+        simulator.step_many(2);
+        let cell = simulator.cell(IVec2::new(0, 1)).unwrap();
+        assert_eq!(cell.matter, sand.0);
+        assert_eq!(cell.velocity(), Vec2::new(0.0, -1.0));
+        assert_eq!(cell.lifetime(), 2);
+        assert_eq!(cell.temperature, AMBIENT_TEMPERATURE);
+        // Empty cells it fell through moved up in its place
+        assert_eq!(simulator.cell(IVec2::new(0, 3)).unwrap().matter, 0);
+        assert_eq!(simulator.cell(IVec2::new(0, 4)), None);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_lifetime_saturates() {
+        let mut simulator = test_simulator(UVec2::new(1, 1), &RuleSet::falling_sand());
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let rock = registry.id("rock").unwrap();
+        let mut cell = Cell::of_matter(rock, &registry.properties());
+        cell.set_lifetime(MAX_LIFETIME - 1);
+        cell.lifetime_variation |= 0x9c00_0000;
+        simulator.replace_cells(&[cell]);
+        simulator.step_many(2);
+        // Saturating at the same lifetime as cell.glsl leaves the color variation as is
+        let cell = simulator.cell(IVec2::ZERO).unwrap();
+        assert_eq!(cell.lifetime(), MAX_LIFETIME);
+        assert_eq!(cell.color_variation(), -100);
+    }
+}
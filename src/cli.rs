@@ -0,0 +1,195 @@
+use bevy::math::UVec2;
+
+use crate::{
+    lenia::LeniaRule, life::LifeRule, rules::RuleMode, DEFAULT_CANVAS_SIZE, DEFAULT_SEED,
+    MAX_CANVAS_SIZE,
+};
+
+pub const USAGE: &str = "Usage: cellular_automata [--canvas-size <WIDTHxHEIGHT>] [--seed <N>] \
+                         [--life <B/S RULE> | --lenia] [--headless [--load <SAVE|PNG>] [--steps \
+                         <N>] [--verify]]";
+
+/// Steps simulated in headless mode unless given
+pub const DEFAULT_HEADLESS_STEPS: u64 = 1000;
+
+/// Command line options
+#[derive(Debug, Clone)]
+pub struct CliArgs {
+    pub canvas_size: UVec2,
+    /// Seed of the simulation's random numbers
+    pub seed: u32,
+    /// Rules simulated, falling sand unless a Life-like rule or Lenia is given
+    pub rules: RuleMode,
+    /// Run the simulation without a window
+    pub headless: bool,
+    /// Steps to run in headless mode
+    pub steps: u64,
+    /// Compare gpu simulation against the cpu reference in headless mode
+    pub verify: bool,
+    /// Save or PNG image to start from in headless mode instead of an empty canvas
+    pub load: Option<String>,
+}
+
+impl CliArgs {
+    /// Parse arguments (excluding the program name)
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliArgs, String> {
+        let mut cli_args = CliArgs {
+            canvas_size: DEFAULT_CANVAS_SIZE,
+            seed: DEFAULT_SEED,
+            rules: RuleMode::FallingSand,
+            headless: false,
+            steps: DEFAULT_HEADLESS_STEPS,
+            verify: false,
+            load: None,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--canvas-size" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "--canvas-size requires a value".to_string())?;
+                    cli_args.canvas_size = parse_canvas_size(&value)?;
+                }
+                "--seed" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "--seed requires a value".to_string())?;
+                    cli_args.seed = value
+                        .parse()
+                        .map_err(|_| format!("Invalid seed {}", value))?;
+                }
+                "--life" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "--life requires a value".to_string())?;
+                    let rule = LifeRule::parse(&value)
+                        .map_err(|e| format!("Invalid life rule {}: {}", value, e))?;
+                    cli_args.rules = RuleMode::Life(rule);
+                }
+                "--lenia" => cli_args.rules = RuleMode::Lenia(LeniaRule::default()),
+                "--headless" => cli_args.headless = true,
+                "--verify" => cli_args.verify = true,
+                "--load" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "--load requires a path".to_string())?;
+                    cli_args.load = Some(value);
+                }
+                "--steps" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "--steps requires a value".to_string())?;
+                    cli_args.steps = value
+                        .parse()
+                        .map_err(|_| format!("Invalid step count {}", value))?;
+                }
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        Ok(cli_args)
+    }
+}
+
+/// Parse canvas size given as `WIDTHxHEIGHT`, e.g. `1024x768`
+pub fn parse_canvas_size(value: &str) -> Result<UVec2, String> {
+    let invalid = || format!("Invalid canvas size {}, expected e.g. 1024x768", value);
+    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+    let size = UVec2::new(
+        width.parse().map_err(|_| invalid())?,
+        height.parse().map_err(|_| invalid())?,
+    );
+    if size.min_element() == 0 || size.max_element() > MAX_CANVAS_SIZE {
+        return Err(format!(
+            "Canvas size {} must be between 1 and {} pixels per side",
+            value, MAX_CANVAS_SIZE
+        ));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliArgs, String> {
+        CliArgs::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_canvas_sizes() {
+        let accepted = [
+            ("1024x768", UVec2::new(1024, 768)),
+            ("1x1", UVec2::new(1, 1)),
+            ("8192x8192", UVec2::new(MAX_CANVAS_SIZE, MAX_CANVAS_SIZE)),
+            ("64x8192", UVec2::new(64, MAX_CANVAS_SIZE)),
+        ];
+        for (value, size) in accepted {
+            assert_eq!(parse_canvas_size(value), Ok(size), "{}", value);
+        }
+        let rejected = [
+            "",
+            "512",
+            "0x0",
+            "0x512",
+            "512x0",
+            "axb",
+            "512xb",
+            "512x",
+            "x512",
+            "-1x512",
+            "512X512",
+            "512x512x2",
+            " 512x512",
+            "8193x512",
+            "512x8193",
+            "99999999999x1",
+        ];
+        for value in rejected {
+            assert!(parse_canvas_size(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_canvas_size_argument() {
+        assert_eq!(parse(&[]).unwrap().canvas_size, DEFAULT_CANVAS_SIZE);
+        let args = parse(&["--canvas-size", "320x200"]).unwrap();
+        assert_eq!(args.canvas_size, UVec2::new(320, 200));
+        assert!(parse(&["--canvas-size"]).is_err());
+        assert!(parse(&["--canvas-size", "320"]).is_err());
+        assert!(parse(&["--canvas-size", "9000x200"]).is_err());
+    }
+
+    #[test]
+    fn parses_headless_arguments() {
+        let args = parse(&[]).unwrap();
+        assert!(!args.headless && !args.verify);
+        assert_eq!(args.steps, DEFAULT_HEADLESS_STEPS);
+        assert_eq!(args.load, None);
+        let args = parse(&[
+            "--headless",
+            "--load",
+            "saves/canvas.cas",
+            "--steps",
+            "50",
+            "--verify",
+        ])
+        .unwrap();
+        assert!(args.headless && args.verify);
+        assert_eq!(args.steps, 50);
+        assert_eq!(args.load.as_deref(), Some("saves/canvas.cas"));
+        let args = parse(&["--headless", "--load", "canvas.png"]).unwrap();
+        assert_eq!(args.load.as_deref(), Some("canvas.png"));
+        assert_eq!(args.steps, DEFAULT_HEADLESS_STEPS);
+        let rejected: [&[&str]; 5] = [
+            &["--headless", "--load"],
+            &["--headless", "--steps"],
+            &["--headless", "--steps", "-1"],
+            &["--headless", "--steps", "many"],
+            &["--headless", "--frames", "10"],
+        ];
+        for args in rejected {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+}
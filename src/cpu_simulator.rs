@@ -0,0 +1,605 @@
+use bevy::math::{IVec2, UVec2, Vec2};
+
+use crate::{
+    ca_simulator::CASimulator,
+    cell::Cell,
+    matter::{MatterId, MatterProperties, MatterReaction, MatterRegistry, MatterState},
+    utils::pcg_hash,
+};
+
+const UP: IVec2 = IVec2::new(0, 1);
+const DOWN: IVec2 = IVec2::new(0, -1);
+const LEFT: IVec2 = IVec2::new(-1, 0);
+const RIGHT: IVec2 = IVec2::new(1, 0);
+const NO_MOVE: IVec2 = IVec2::ZERO;
+const RANDOM_SLIDE: u32 = 1;
+const RANDOM_SPREAD: u32 = 2;
+const NEIGHBORS: [IVec2; 4] = [UP, DOWN, LEFT, RIGHT];
+
+/// CPU reference implementation of the simulation kernels. Mirrors the compute shaders rule by
+/// rule and indexes its grid like [`CASimulator`], so both must produce identical grids.
+pub struct CpuSimulator {
+    canvas_size: UVec2,
+    properties: Vec<MatterProperties>,
+    reactions: Vec<MatterReaction>,
+    cells: Vec<Cell>,
+    seed: u32,
+    steps: u64,
+}
+
+impl CpuSimulator {
+    pub fn new(matter_registry: &MatterRegistry, canvas_size: UVec2, seed: u32) -> CpuSimulator {
+        CpuSimulator {
+            canvas_size,
+            properties: matter_registry.properties(),
+            reactions: matter_registry.reactions(),
+            cells: vec![Cell::EMPTY; (canvas_size.x * canvas_size.y) as usize],
+            seed,
+            steps: 0,
+        }
+    }
+
+    /// Current matter grid
+    pub fn matter(&self) -> Vec<u32> {
+        self.cells.iter().map(|cell| cell.matter).collect()
+    }
+
+    /// Replace the grid with new cells of given matter, like [`CASimulator::set_matter`]
+    pub fn set_matter(&mut self, matter: &[u32]) {
+        assert_eq!(matter.len(), self.cells.len());
+        self.cells = matter
+            .iter()
+            .map(|&m| Cell::of_matter(MatterId(m), &self.properties))
+            .collect();
+    }
+
+    /// Step simulation, running kernels in the same order as [`CASimulator::step`]
+    pub fn step(&mut self) {
+        self.run_kernel(Self::react);
+        self.run_kernel(Self::heat);
+        self.run_kernel(Self::fall_empty);
+        self.run_kernel(Self::rise_empty);
+        self.run_kernel(Self::slide_down_empty);
+        self.run_kernel(Self::horizontal_empty);
+        self.steps += 1;
+    }
+
+    /// Like a dispatch: every cell's next state is computed from the current grid only
+    fn run_kernel(&mut self, kernel: fn(&Self, IVec2) -> Cell) {
+        let width = self.canvas_size.x as i32;
+        let next = (0..self.cells.len() as i32)
+            .map(|i| kernel(self, IVec2::new(i % width, i / width)))
+            .collect();
+        self.cells = next;
+    }
+
+    fn index(&self, pos: IVec2) -> usize {
+        (pos.y * self.canvas_size.x as i32 + pos.x) as usize
+    }
+
+    fn is_inside(&self, pos: IVec2) -> bool {
+        pos.x >= 0
+            && pos.x < self.canvas_size.x as i32
+            && pos.y >= 0
+            && pos.y < self.canvas_size.y as i32
+    }
+
+    fn read_cell(&self, pos: IVec2) -> Cell {
+        self.cells[self.index(pos)]
+    }
+
+    fn read_matter(&self, pos: IVec2) -> u32 {
+        self.read_cell(pos).matter
+    }
+
+    /// Cell at pos moving by dir, like `moved_cell` in includes.glsl
+    fn moved_cell(&self, pos: IVec2, dir: IVec2) -> Cell {
+        let mut cell = self.read_cell(pos);
+        cell.set_velocity(cell.velocity() + dir.as_vec2());
+        cell
+    }
+
+    fn state(&self, matter: u32) -> u32 {
+        self.properties[matter as usize].state
+    }
+
+    fn density(&self, matter: u32) -> f32 {
+        self.properties[matter as usize].density
+    }
+
+    fn is_empty(&self, matter: u32) -> bool {
+        matter == MatterId::EMPTY.0
+    }
+
+    fn is_powder(&self, matter: u32) -> bool {
+        self.state(matter) == MatterState::Powder as u32
+    }
+
+    fn is_liquid(&self, matter: u32) -> bool {
+        self.state(matter) == MatterState::Liquid as u32
+    }
+
+    fn is_gas(&self, matter: u32) -> bool {
+        self.state(matter) == MatterState::Gas as u32
+    }
+
+    fn is_fluid(&self, matter: u32) -> bool {
+        self.is_empty(matter) || self.is_liquid(matter) || self.is_gas(matter)
+    }
+
+    fn is_empty_at(&self, pos: IVec2) -> bool {
+        self.is_inside(pos) && self.is_empty(self.read_matter(pos))
+    }
+
+    fn is_blocked(&self, pos: IVec2, dir: IVec2) -> bool {
+        !self.is_empty_at(pos + dir)
+    }
+
+    /// react.glsl
+    fn react(&self, pos: IVec2) -> Cell {
+        let mut cell = self.read_cell(pos);
+        let mut lifetime = cell.lifetime() + 1;
+        let properties = self.properties[cell.matter as usize];
+        let start = properties.reactions_start as usize;
+        let reactions = &self.reactions[start..start + properties.reactions_len as usize];
+        for reaction in reactions {
+            let touches = (-1..=1).any(|y| {
+                (-1..=1).any(|x| {
+                    let neighbor = pos + IVec2::new(x, y);
+                    (x != 0 || y != 0)
+                        && self.is_inside(neighbor)
+                        && self.read_matter(neighbor) == reaction.reagent
+                })
+            });
+            if touches {
+                cell.matter = reaction.product;
+                cell.temperature = self.properties[reaction.product as usize].temperature;
+                lifetime = 0;
+                break;
+            }
+        }
+        cell.set_lifetime(lifetime);
+        cell.set_velocity(Vec2::ZERO);
+        cell
+    }
+
+    fn conductivity_between(&self, a: u32, b: u32) -> f32 {
+        self.properties[a as usize]
+            .conductivity
+            .min(self.properties[b as usize].conductivity)
+    }
+
+    /// heat.glsl
+    fn heat(&self, pos: IVec2) -> Cell {
+        let mut cell = self.read_cell(pos);
+        let mut flow = 0.0;
+        for neighbor in NEIGHBORS.map(|dir| pos + dir) {
+            if self.is_inside(neighbor) {
+                let other = self.read_cell(neighbor);
+                flow += self.conductivity_between(cell.matter, other.matter)
+                    * (other.temperature - cell.temperature);
+            }
+        }
+        cell.temperature += 0.25 * flow;
+        let properties = self.properties[cell.matter as usize];
+        let matter = if cell.temperature > properties.heated_above {
+            properties.heated_into
+        } else if cell.temperature < properties.cooled_below {
+            properties.cooled_into
+        } else {
+            cell.matter
+        };
+        if matter != cell.matter {
+            cell.matter = matter;
+            cell.set_lifetime(0);
+        }
+        cell
+    }
+
+    fn falls_into(&self, from: u32, to: u32) -> bool {
+        (self.is_powder(from) || self.is_liquid(from))
+            && self.is_fluid(to)
+            && (self.is_empty(to) || self.density(from) > self.density(to))
+    }
+
+    fn wants_to_fall(&self, pos: IVec2) -> bool {
+        self.is_inside(pos + DOWN)
+            && self.falls_into(self.read_matter(pos), self.read_matter(pos + DOWN))
+    }
+
+    fn falls(&self, pos: IVec2) -> bool {
+        self.wants_to_fall(pos)
+            && (self.is_empty(self.read_matter(pos + DOWN)) || !self.wants_to_fall(pos + DOWN))
+    }
+
+    /// fall_empty.glsl
+    fn fall_empty(&self, pos: IVec2) -> Cell {
+        if self.is_inside(pos + UP) && self.falls(pos + UP) {
+            self.moved_cell(pos + UP, DOWN)
+        } else if self.falls(pos) {
+            self.moved_cell(pos + DOWN, UP)
+        } else {
+            self.read_cell(pos)
+        }
+    }
+
+    fn rises_into(&self, from: u32, to: u32) -> bool {
+        self.is_gas(from)
+            && self.is_fluid(to)
+            && (self.is_empty(to) || self.density(from) < self.density(to))
+    }
+
+    fn wants_to_rise(&self, pos: IVec2) -> bool {
+        self.is_inside(pos + UP)
+            && self.rises_into(self.read_matter(pos), self.read_matter(pos + UP))
+    }
+
+    fn rises(&self, pos: IVec2) -> bool {
+        self.wants_to_rise(pos)
+            && (self.is_empty(self.read_matter(pos + UP)) || !self.wants_to_rise(pos + UP))
+    }
+
+    /// rise_empty.glsl
+    fn rise_empty(&self, pos: IVec2) -> Cell {
+        if self.is_inside(pos + DOWN) && self.rises(pos + DOWN) {
+            self.moved_cell(pos + DOWN, UP)
+        } else if self.rises(pos) {
+            self.moved_cell(pos + UP, DOWN)
+        } else {
+            self.read_cell(pos)
+        }
+    }
+
+    /// includes.glsl
+    fn random_bool(&self, pos: IVec2, salt: u32) -> bool {
+        random_uint(self.seed, self.steps as u32, pos, salt) >> 31 == 1
+    }
+
+    /// Left or right, at random if both are possible
+    fn pick_direction(&self, pos: IVec2, salt: u32, left: bool, right: bool) -> IVec2 {
+        match (left, right) {
+            (true, true) if self.random_bool(pos, salt) => LEFT,
+            (true, true) => RIGHT,
+            (true, false) => LEFT,
+            (false, true) => RIGHT,
+            (false, false) => NO_MOVE,
+        }
+    }
+
+    fn slide_direction(&self, pos: IVec2) -> IVec2 {
+        let matter = self.read_matter(pos);
+        if !(self.is_powder(matter) || self.is_liquid(matter)) || !self.is_blocked(pos, DOWN) {
+            return NO_MOVE;
+        }
+        self.pick_direction(
+            pos,
+            RANDOM_SLIDE,
+            self.is_empty_at(pos + DOWN + LEFT),
+            self.is_empty_at(pos + DOWN + RIGHT),
+        )
+    }
+
+    /// slide_down_empty.glsl
+    fn slide_down_empty(&self, pos: IVec2) -> Cell {
+        self.move_sideways(pos, DOWN, Self::slide_direction)
+    }
+
+    fn spread_direction(&self, pos: IVec2) -> IVec2 {
+        let matter = self.read_matter(pos);
+        let spreads = (self.is_liquid(matter) && self.is_blocked(pos, DOWN))
+            || (self.is_gas(matter) && self.is_blocked(pos, UP));
+        if !spreads {
+            return NO_MOVE;
+        }
+        self.pick_direction(
+            pos,
+            RANDOM_SPREAD,
+            self.is_empty_at(pos + LEFT),
+            self.is_empty_at(pos + RIGHT),
+        )
+    }
+
+    /// horizontal_empty.glsl
+    fn horizontal_empty(&self, pos: IVec2) -> Cell {
+        self.move_sideways(pos, IVec2::ZERO, Self::spread_direction)
+    }
+
+    /// Shared conflict resolution of sliding & spreading: matter moves to `pos + vertical + dir`,
+    /// swapping places with the empty cell there, and matter moving left wins a cell targeted from
+    /// both sides.
+    fn move_sideways(
+        &self,
+        pos: IVec2,
+        vertical: IVec2,
+        direction: fn(&Self, IVec2) -> IVec2,
+    ) -> Cell {
+        let current = self.read_cell(pos);
+        let moves_to =
+            |from: IVec2, dir: IVec2| self.is_inside(from) && direction(self, from) == dir;
+        if self.is_empty(current.matter) {
+            if moves_to(pos - vertical + RIGHT, LEFT) {
+                return self.moved_cell(pos - vertical + RIGHT, vertical + LEFT);
+            } else if moves_to(pos - vertical + LEFT, RIGHT) {
+                return self.moved_cell(pos - vertical + LEFT, vertical + RIGHT);
+            }
+        } else {
+            let dir = direction(self, pos);
+            let accepted = dir == LEFT || (dir == RIGHT && !moves_to(pos + 2 * RIGHT, LEFT));
+            if accepted {
+                return self.moved_cell(pos + vertical + dir, -(vertical + dir));
+            }
+        }
+        current
+    }
+}
+
+/// Same random numbers as `random_uint` in includes.glsl
+fn random_uint(seed: u32, steps: u32, pos: IVec2, salt: u32) -> u32 {
+    let mut hash = pcg_hash(seed);
+    hash = pcg_hash(hash ^ pos.x as u32);
+    hash = pcg_hash(hash ^ pos.y as u32);
+    hash = pcg_hash(hash ^ steps);
+    pcg_hash(hash ^ salt)
+}
+
+/// Grid randomly filled with matters, `fill` being the share of non-empty cells. Empty if the
+/// empty matter is the only one.
+pub fn random_grid(matter_count: u32, canvas_size: UVec2, fill: f32, seed: u64) -> Vec<u32> {
+    let len = (canvas_size.x * canvas_size.y) as usize;
+    if matter_count <= 1 {
+        return vec![MatterId::EMPTY.0; len];
+    }
+    // Xorshift is plenty for test data and keeps grids reproducible
+    let mut state = seed.max(1);
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    (0..len)
+        .map(|_| {
+            if (next() % 1000) as f32 / 1000.0 < fill {
+                1 + (next() % (matter_count as u64 - 1)) as u32
+            } else {
+                MatterId::EMPTY.0
+            }
+        })
+        .collect()
+}
+
+/// First difference between GPU and CPU grids
+#[derive(Debug)]
+pub struct Mismatch {
+    pub step: u64,
+    pub pos: IVec2,
+    pub gpu: u32,
+    pub cpu: u32,
+}
+
+/// Run both simulations from the same grid and compare them grid by grid after each step. The
+/// simulators must have the same seed.
+pub fn compare_with_gpu(
+    gpu: &mut CASimulator,
+    cpu: &mut CpuSimulator,
+    initial: &[u32],
+    steps: u64,
+) -> Result<(), Mismatch> {
+    assert_eq!(gpu.seed(), cpu.seed);
+    gpu.set_matter(initial);
+    gpu.set_steps(0);
+    cpu.set_matter(initial);
+    cpu.steps = 0;
+    let width = cpu.canvas_size.x as i32;
+    for step in 1..=steps {
+        gpu.step();
+        cpu.step();
+        let gpu_matter = gpu.matter();
+        let cpu_matter = cpu.matter();
+        if let Some(i) = (0..gpu_matter.len()).find(|&i| gpu_matter[i] != cpu_matter[i]) {
+            return Err(Mismatch {
+                step,
+                pos: IVec2::new(i as i32 % width, i as i32 / width),
+                gpu: gpu_matter[i],
+                cpu: cpu_matter[i],
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cell::AMBIENT_TEMPERATURE, headless::test_simulator, matter::MATTER_DEFINITIONS_PATH,
+        rules::RuleSet,
+    };
+
+    /// Matters without reactions, thus movement alone must conserve them
+    const INERT_MATTERS: &str = r#"(matters: [
+        (name: "empty", color: 0, state: Empty, density: 0.0),
+        (name: "sand", color: 0, state: Powder, density: 1.6),
+        (name: "water", color: 0, state: Liquid, density: 1.0),
+        (name: "rock", color: 0, state: Solid, density: 2.6),
+        (name: "steam", color: 0, state: Gas, density: 0.3),
+    ])"#;
+
+    fn counts(matter: &[u32], matter_count: usize) -> Vec<usize> {
+        (0..matter_count as u32)
+            .map(|m| matter.iter().filter(|&&cell| cell == m).count())
+            .collect()
+    }
+
+    #[test]
+    fn sand_falls_to_bottom() {
+        let registry = MatterRegistry::from_ron(INERT_MATTERS).unwrap();
+        let sand = registry.id("sand").unwrap().0;
+        let mut sim = CpuSimulator::new(&registry, UVec2::new(3, 8), 0);
+        let mut grid = vec![0; 24];
+        grid[sim.index(IVec2::new(1, 7))] = sand;
+        sim.set_matter(&grid);
+        for _ in 0..8 {
+            sim.step();
+        }
+        assert_eq!(sim.read_matter(IVec2::new(1, 0)), sand);
+        assert_eq!(counts(&sim.matter(), 5)[sand as usize], 1);
+    }
+
+    #[test]
+    fn sand_sinks_through_water() {
+        let registry = MatterRegistry::from_ron(INERT_MATTERS).unwrap();
+        let (sand, water) = (
+            registry.id("sand").unwrap().0,
+            registry.id("water").unwrap().0,
+        );
+        let mut sim = CpuSimulator::new(&registry, UVec2::new(1, 4), 0);
+        sim.set_matter(&[water, water, water, sand]);
+        for _ in 0..4 {
+            sim.step();
+        }
+        assert_eq!(sim.matter(), &[sand, water, water, water]);
+    }
+
+    #[test]
+    fn water_spreads_on_floor() {
+        let registry = MatterRegistry::from_ron(INERT_MATTERS).unwrap();
+        let water = registry.id("water").unwrap().0;
+        let mut sim = CpuSimulator::new(&registry, UVec2::new(5, 3), 0);
+        let mut grid = vec![0; 15];
+        for y in 0..3 {
+            grid[sim.index(IVec2::new(2, y))] = water;
+        }
+        sim.set_matter(&grid);
+        for _ in 0..10 {
+            sim.step();
+        }
+        let floor = (0..5).filter(|&x| sim.read_matter(IVec2::new(x, 0)) == water);
+        assert_eq!(floor.count(), 3);
+    }
+
+    #[test]
+    fn same_seed_reproduces_run() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let size = UVec2::new(40, 30);
+        let grid = random_grid(registry.matters().count() as u32, size, 0.4, 3);
+        let run = |seed| {
+            let mut sim = CpuSimulator::new(&registry, size, seed);
+            sim.set_matter(&grid);
+            for _ in 0..30 {
+                sim.step();
+            }
+            sim.matter()
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn movement_conserves_matter() {
+        let registry = MatterRegistry::from_ron(INERT_MATTERS).unwrap();
+        let size = UVec2::new(37, 29);
+        let mut sim = CpuSimulator::new(&registry, size, 0);
+        let grid = random_grid(5, size, 0.4, 7);
+        sim.set_matter(&grid);
+        for _ in 0..50 {
+            sim.step();
+        }
+        assert_eq!(counts(&sim.matter(), 5), counts(&grid, 5));
+    }
+
+    #[test]
+    fn random_grid_without_matters_is_empty() {
+        let grid = random_grid(1, UVec2::new(4, 3), 0.5, 1);
+        assert_eq!(grid, vec![MatterId::EMPTY.0; 12]);
+    }
+
+    #[test]
+    fn water_touching_fire_turns_to_steam() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let (water, fire, steam) = (
+            registry.id("water").unwrap().0,
+            registry.id("fire").unwrap().0,
+            registry.id("steam").unwrap().0,
+        );
+        let mut sim = CpuSimulator::new(&registry, UVec2::new(2, 1), 0);
+        sim.set_matter(&[water, fire]);
+        sim.run_kernel(CpuSimulator::react);
+        assert_eq!(sim.read_matter(IVec2::new(0, 0)), steam);
+    }
+
+    #[test]
+    fn heat_spreads_and_is_conserved() {
+        let registry = MatterRegistry::from_ron(INERT_MATTERS).unwrap();
+        let rock = registry.id("rock").unwrap().0;
+        let mut sim = CpuSimulator::new(&registry, UVec2::new(5, 1), 0);
+        sim.set_matter(&[rock; 5]);
+        sim.cells[0].temperature = 520.0;
+        let total = |sim: &CpuSimulator| sim.cells.iter().map(|c| c.temperature).sum::<f32>();
+        let before = total(&sim);
+        for _ in 0..100 {
+            sim.run_kernel(CpuSimulator::heat);
+        }
+        assert!(sim.cells[4].temperature > AMBIENT_TEMPERATURE);
+        assert!(sim.cells[0].temperature > sim.cells[4].temperature);
+        assert!((total(&sim) - before).abs() < 0.1);
+    }
+
+    #[test]
+    fn lava_boils_water_and_cools_into_rock() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let (water, lava, steam, rock) = (
+            registry.id("water").unwrap().0,
+            registry.id("lava").unwrap().0,
+            registry.id("steam").unwrap().0,
+            registry.id("rock").unwrap().0,
+        );
+        let mut sim = CpuSimulator::new(&registry, UVec2::new(2, 1), 0);
+        sim.set_matter(&[water, lava]);
+        sim.run_kernel(CpuSimulator::heat);
+        assert_eq!(sim.matter(), &[steam, lava]);
+        // Losing heat to fresh water, lava solidifies
+        for _ in 0..20 {
+            sim.cells[0] = Cell::of_matter(MatterId(water), &sim.properties);
+            sim.run_kernel(CpuSimulator::heat);
+        }
+        assert_eq!(sim.read_matter(IVec2::new(1, 0)), rock);
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_matches_cpu() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        // Not a multiple of work group size on purpose
+        let size = UVec2::new(100, 70);
+        let mut gpu = test_simulator(size, &RuleSet::falling_sand());
+        gpu.set_seed(7);
+        let mut cpu = CpuSimulator::new(&registry, size, 7);
+        let initial = random_grid(registry.matters().count() as u32, size, 0.3, 42);
+        if let Err(mismatch) = compare_with_gpu(&mut gpu, &mut cpu, &initial, 100) {
+            panic!("Gpu and cpu simulations differ: {:?}", mismatch);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn batched_steps_match_single_steps() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let size = UVec2::new(64, 64);
+        let initial = random_grid(registry.matters().count() as u32, size, 0.3, 7);
+        let new_simulator = || {
+            let mut simulator = test_simulator(size, &RuleSet::falling_sand());
+            simulator.set_seed(3);
+            simulator.set_matter(&initial);
+            simulator
+        };
+        let mut single = new_simulator();
+        for _ in 0..20 {
+            single.step();
+        }
+        let mut batched = new_simulator();
+        batched.step_many(20);
+        assert_eq!(batched.steps(), 20);
+        assert_eq!(single.matter(), batched.matter());
+    }
+}
@@ -0,0 +1,60 @@
+/// A pair of buffers of which one holds the current state and the other receives the next state.
+/// Each swap advances the generation, so the buffer that received the next state is read as
+/// `current` right after it.
+pub struct DoubleBuffer<T> {
+    buffers: [T; 2],
+    generation: u64,
+}
+
+impl<T> DoubleBuffer<T> {
+    pub fn new(first: T, second: T) -> DoubleBuffer<T> {
+        DoubleBuffer {
+            buffers: [first, second],
+            generation: 0,
+        }
+    }
+
+    /// Buffer holding the latest state. Kernels read from it and drawing writes into it.
+    pub fn current(&self) -> &T {
+        self.at_parity(self.parity()).0
+    }
+
+    /// Index of the current buffer, alternating with each swap. Anything bound to the buffers in
+    /// their current & next roles, e.g. descriptor sets, can be cached per parity.
+    pub fn parity(&self) -> usize {
+        (self.generation % 2) as usize
+    }
+
+    /// Current buffer & the one into which the next state is written, at a parity
+    pub fn at_parity(&self, parity: usize) -> (&T, &T) {
+        (&self.buffers[parity], &self.buffers[(parity + 1) % 2])
+    }
+
+    /// Make the next state current
+    pub fn swap(&mut self) {
+        self.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swaps_current_and_next() {
+        let mut buffers = DoubleBuffer::new("first", "second");
+        assert_eq!(buffers.parity(), 0);
+        assert_eq!(*buffers.current(), "first");
+        assert_eq!(buffers.at_parity(0), (&"first", &"second"));
+        buffers.swap();
+        // The buffer that received the next state is current now
+        assert_eq!(buffers.parity(), 1);
+        assert_eq!(*buffers.current(), "second");
+        assert_eq!(buffers.at_parity(1), (&"second", &"first"));
+        buffers.swap();
+        assert_eq!(buffers.parity(), 0);
+        assert_eq!(*buffers.current(), "first");
+        // Roles at a parity don't depend on the swaps so far, so what's cached per parity holds
+        assert_eq!(buffers.at_parity(1), (&"second", &"first"));
+    }
+}
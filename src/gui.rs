@@ -1,24 +1,284 @@
 use bevy::{
     diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin},
     prelude::*,
+    window::PrimaryWindow,
 };
 use bevy_vulkano::{
     egui_winit_vulkano::{egui, egui::Ui},
     BevyVulkanoWindows,
 };
+use strum::IntoEnumIterator;
 
-use crate::DynamicSettings;
+use crate::{
+    brush::BrushTool,
+    ca_simulator::CASimulator,
+    camera::OrthographicCamera,
+    cell::Cell,
+    lenia::{GrowthFunction, LeniaRule, MAX_LENIA_RADIUS, MIN_LENIA_RADIUS},
+    life::{LifeRule, LIFE_PRESETS},
+    matter::{MatterId, MatterRegistry},
+    rules::RuleMode,
+    sim_clock::{ClockMode, SimClock, MAX_STEPS_PER_FRAME},
+    utils::world_to_window,
+    ChangeRules, CurrentMousePos, DragStart, DynamicSettings, ImportImage, LoadCanvas,
+    RecreateCanvas, RedoStroke, SaveCanvas, TakeScreenshot, UndoStroke, MATTER_SHORTCUTS,
+    MAX_CANVAS_SIZE,
+};
 
 /// Give our text a custom size
 fn sized_text(ui: &mut Ui, text: impl Into<String>, size: f32) {
     ui.label(egui::RichText::new(text).size(size));
 }
 
+/// Small square filled with matter's RGBA color
+fn color_swatch(ui: &mut Ui, color: u32) {
+    let [r, g, b, a] = color.to_be_bytes();
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(14.0, 14.0), egui::Sense::hover());
+    ui.painter()
+        .rect_filled(rect, 2.0, egui::Color32::from_rgba_unmultiplied(r, g, b, a));
+}
+
+/// Palette of all matters. Selecting the empty matter erases.
+fn matter_palette(ui: &mut Ui, registry: &MatterRegistry, settings: &mut DynamicSettings) {
+    for (id, definition) in registry.matters() {
+        let name = if id == MatterId::EMPTY {
+            "Erase".to_string()
+        } else {
+            definition.name.clone()
+        };
+        let label = match MATTER_SHORTCUTS.get(id.0 as usize) {
+            Some(_) => format!("[{}] {}", id.0, name),
+            None => name,
+        };
+        ui.horizontal(|ui| {
+            color_swatch(ui, definition.color);
+            if ui
+                .selectable_label(settings.draw_matter == id, label)
+                .clicked()
+            {
+                settings.draw_matter = id;
+            }
+        });
+    }
+}
+
+/// Brush tool, its options and the matter it may replace
+fn brush_controls(ui: &mut Ui, registry: &MatterRegistry, settings: &mut DynamicSettings) {
+    ui.horizontal_wrapped(|ui| {
+        for tool in BrushTool::iter() {
+            ui.selectable_value(&mut settings.brush_tool, tool, tool.to_string());
+        }
+    });
+    ui.add(egui::Slider::new(&mut settings.brush_radius, 0.5..=30.0).text("Brush Radius"));
+    if settings.brush_tool == BrushTool::Spray {
+        ui.add(egui::Slider::new(&mut settings.spray_density, 0.01..=1.0).text("Spray Density"));
+    }
+    let name = |id: Option<MatterId>| match id.and_then(|id| registry.get(id)) {
+        Some(definition) => definition.name.clone(),
+        None => "Any matter".to_string(),
+    };
+    egui::ComboBox::from_label("Replace only")
+        .selected_text(name(settings.replace_only))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut settings.replace_only, None, name(None));
+            for (id, definition) in registry.matters() {
+                ui.selectable_value(&mut settings.replace_only, Some(id), &definition.name);
+            }
+        });
+}
+
+/// Outline of the line or rectangle being dragged, drawn once the mouse is released
+pub fn drag_preview(
+    windows: NonSend<BevyVulkanoWindows>,
+    window_query: Query<(Entity, &Window), With<PrimaryWindow>>,
+    camera: Res<OrthographicCamera>,
+    settings: Res<DynamicSettings>,
+    drag_start: Res<DragStart>,
+    current: Res<CurrentMousePos>,
+) {
+    let (start, current) = match (drag_start.0, current.0) {
+        (Some(start), Some(current)) if settings.brush_tool.is_drag() => (start, current),
+        _ => return,
+    };
+    let (window_entity, window) = window_query.single();
+    let primary_window = windows.get_vulkano_window(window_entity).unwrap();
+    let to_screen = |world| {
+        let pos = world_to_window(window, camera.pos, camera.scale, world);
+        egui::pos2(pos.x, pos.y)
+    };
+    let (start, end) = (to_screen(start.world), to_screen(current.world));
+    let painter = primary_window
+        .gui
+        .context()
+        .layer_painter(egui::LayerId::new(
+            egui::Order::Foreground,
+            egui::Id::new("drag_preview"),
+        ));
+    let stroke = egui::Stroke::new(1.0, egui::Color32::WHITE);
+    if settings.brush_tool == BrushTool::Line {
+        painter.line_segment([start, end], stroke);
+    } else {
+        painter.rect_stroke(egui::Rect::from_two_pos(start, end), 0.0, stroke);
+    }
+}
+
+/// Fields of the cell under the mouse
+fn cell_info(ui: &mut Ui, registry: &MatterRegistry, cell: &Cell, size: f32) {
+    let matter = registry
+        .get(MatterId(cell.matter))
+        .map_or_else(|| format!("{:#x}", cell.matter), |m| m.name.clone());
+    let velocity = cell.velocity();
+    sized_text(ui, format!("Matter: {}", matter), size);
+    sized_text(ui, format!("Temperature: {:.1} °C", cell.temperature), size);
+    sized_text(
+        ui,
+        format!("Velocity: {:.1}, {:.1}", velocity.x, velocity.y),
+        size,
+    );
+    sized_text(ui, format!("Lifetime: {}", cell.lifetime()), size);
+    sized_text(
+        ui,
+        format!("Color variation: {}", cell.color_variation()),
+        size,
+    );
+    sized_text(ui, format!("State: {:.3}", cell.state), size);
+}
+
+/// Pause, single step and speed controls
+fn clock_controls(ui: &mut Ui, clock: &mut SimClock) {
+    ui.horizontal(|ui| {
+        let label = if clock.paused {
+            "Resume (Space)"
+        } else {
+            "Pause (Space)"
+        };
+        if ui.button(label).clicked() {
+            clock.toggle_pause();
+        }
+        if ui.button("Step (.)").clicked() {
+            clock.step_once();
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.radio_value(&mut clock.mode, ClockMode::PerFrame, "Per frame");
+        ui.radio_value(&mut clock.mode, ClockMode::FixedTimestep, "Fixed rate");
+    });
+    match clock.mode {
+        ClockMode::PerFrame => ui.add(
+            egui::Slider::new(&mut clock.steps_per_frame, 1..=MAX_STEPS_PER_FRAME)
+                .text("Steps per frame"),
+        ),
+        ClockMode::FixedTimestep => ui.add(
+            egui::Slider::new(&mut clock.steps_per_second, 1.0..=1000.0)
+                .logarithmic(true)
+                .text("Steps per second"),
+        ),
+    };
+}
+
+/// Life-like rule string with presets. Returns the rule to apply, if any.
+fn life_controls(ui: &mut Ui, settings: &mut DynamicSettings) -> Option<RuleMode> {
+    let mut apply = None;
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_source("life_presets")
+            .selected_text("Presets")
+            .show_ui(ui, |ui| {
+                for (name, rule) in LIFE_PRESETS {
+                    if ui.button(format!("{} {}", name, rule)).clicked() {
+                        settings.life_rule = rule.to_string();
+                        apply = Some(RuleMode::Life(LifeRule::parse(rule).unwrap()));
+                    }
+                }
+            });
+        ui.text_edit_singleline(&mut settings.life_rule);
+    });
+    match LifeRule::parse(&settings.life_rule) {
+        Ok(rule) => {
+            if ui.button("Apply rule").clicked() {
+                apply = Some(RuleMode::Life(rule));
+            }
+        }
+        Err(e) => {
+            ui.colored_label(egui::Color32::RED, e.to_string());
+        }
+    }
+    apply
+}
+
+/// Lenia kernel & growth parameters. Returns the rule to apply, if any.
+fn lenia_controls(ui: &mut Ui, lenia: &mut LeniaRule) -> Option<RuleMode> {
+    ui.add(
+        egui::Slider::new(&mut lenia.radius, MIN_LENIA_RADIUS..=MAX_LENIA_RADIUS).text("Radius"),
+    );
+    ui.add(egui::Slider::new(&mut lenia.mu, 0.0..=1.0).text("Growth center"));
+    ui.add(
+        egui::Slider::new(&mut lenia.sigma, 0.001..=0.5)
+            .logarithmic(true)
+            .text("Growth width"),
+    );
+    ui.add(egui::Slider::new(&mut lenia.dt, 0.01..=1.0).text("Time step"));
+    egui::ComboBox::from_label("Growth function")
+        .selected_text(lenia.growth.to_string())
+        .show_ui(ui, |ui| {
+            for growth in GrowthFunction::iter() {
+                ui.selectable_value(&mut lenia.growth, growth, growth.to_string());
+            }
+        });
+    ui.button("Apply rule")
+        .clicked()
+        .then_some(RuleMode::Lenia(*lenia))
+}
+
+/// Falling sand, a Life-like rule or Lenia, switched without clearing the canvas. Returns whether
+/// the rules in the settings changed.
+fn rule_controls(ui: &mut Ui, settings: &mut DynamicSettings) -> bool {
+    let lenia = RuleMode::Lenia(settings.lenia);
+    let mut rules = settings.rules;
+    ui.horizontal(|ui| {
+        ui.radio_value(&mut rules, RuleMode::FallingSand, "Falling sand");
+        let is_life = matches!(rules, RuleMode::Life(_));
+        if ui.radio(is_life, "Life-like").clicked() && !is_life {
+            // Start over from Conway's rule if the rule string was left invalid
+            let rule = LifeRule::parse(&settings.life_rule).unwrap_or_else(|_| {
+                settings.life_rule = LifeRule::CONWAY.to_string();
+                LifeRule::CONWAY
+            });
+            rules = RuleMode::Life(rule);
+        }
+        let is_lenia = matches!(rules, RuleMode::Lenia(_));
+        if ui.radio(is_lenia, "Lenia").clicked() && !is_lenia {
+            rules = lenia;
+        }
+    });
+    let apply = match rules {
+        RuleMode::FallingSand => None,
+        RuleMode::Life(_) => life_controls(ui, settings),
+        RuleMode::Lenia(_) => lenia_controls(ui, &mut settings.lenia),
+    };
+    let rules = apply.unwrap_or(rules);
+    let changed = rules != settings.rules;
+    settings.rules = rules;
+    changed
+}
+
 /// System to generate user interface with egui
+#[allow(clippy::too_many_arguments)]
 pub fn user_interface(
     diagnostics: Res<Diagnostics>,
     windows: NonSend<BevyVulkanoWindows>,
     mut settings: ResMut<DynamicSettings>,
+    mut clock: ResMut<SimClock>,
+    registry: Res<MatterRegistry>,
+    simulator: Res<CASimulator>,
+    mut recreate_canvas: EventWriter<RecreateCanvas>,
+    mut change_rules: EventWriter<ChangeRules>,
+    mut save_canvas: EventWriter<SaveCanvas>,
+    mut load_canvas: EventWriter<LoadCanvas>,
+    mut import_image: EventWriter<ImportImage>,
+    mut take_screenshot: EventWriter<TakeScreenshot>,
+    mut undo_stroke: EventWriter<UndoStroke>,
+    mut redo_stroke: EventWriter<RedoStroke>,
+    current: Res<CurrentMousePos>,
     window_query: Query<Entity, With<Window>>,
 ) {
     let window_entity = window_query.single();
@@ -34,7 +294,91 @@ pub fn user_interface(
                     sized_text(ui, format!("FPS: {:.2}", avg), size);
                 }
             }
+            sized_text(ui, format!("Step: {}", simulator.steps()), size);
+            // Reading a cell back waits for the gpu, thus only when asked to
+            ui.checkbox(&mut settings.inspect_cell, "Inspect cell");
+            if settings.inspect_cell {
+                let hovered = current
+                    .0
+                    .and_then(|pos| simulator.cell(pos.canvas_cell(simulator.canvas_size())));
+                if let Some(cell) = hovered {
+                    cell_info(ui, &registry, &cell, size);
+                }
+            }
+            ui.heading("Simulation");
+            clock_controls(ui, &mut clock);
+            ui.heading("Rules");
+            let previous = settings.rules;
+            if rule_controls(ui, &mut settings) {
+                change_rules.send(ChangeRules {
+                    previous,
+                });
+            }
+            ui.heading("Brush");
+            brush_controls(ui, &registry, &mut settings);
+            ui.horizontal(|ui| {
+                if ui.button("Undo (Ctrl+Z)").clicked() {
+                    undo_stroke.send(UndoStroke);
+                }
+                if ui.button("Redo (Ctrl+Y)").clicked() {
+                    redo_stroke.send(RedoStroke);
+                }
+            });
             ui.heading("Settings");
-            ui.add(egui::Slider::new(&mut settings.brush_radius, 0.5..=30.0).text("Brush Radius"));
+            ui.horizontal(|ui| {
+                ui.label("Canvas");
+                ui.add(
+                    egui::DragValue::new(&mut settings.canvas_size.x)
+                        .clamp_range(1..=MAX_CANVAS_SIZE),
+                );
+                ui.label("x");
+                ui.add(
+                    egui::DragValue::new(&mut settings.canvas_size.y)
+                        .clamp_range(1..=MAX_CANVAS_SIZE),
+                );
+                if ui.button("Recreate").clicked() {
+                    recreate_canvas.send(RecreateCanvas);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Seed");
+                ui.add(egui::DragValue::new(&mut settings.seed));
+            });
+            ui.horizontal(|ui| {
+                ui.label("File");
+                ui.text_edit_singleline(&mut settings.save_path);
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Save (Ctrl+S)").clicked() {
+                    save_canvas.send(SaveCanvas);
+                }
+                if ui.button("Load (Ctrl+O)").clicked() {
+                    load_canvas.send(LoadCanvas);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Image");
+                ui.text_edit_singleline(&mut settings.image_path);
+            });
+            if ui.button("Import PNG (Ctrl+I)").clicked() {
+                import_image.send(ImportImage);
+            }
+            if ui.button("Screenshot (F12)").clicked() {
+                take_screenshot.send(TakeScreenshot);
+            }
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut settings.recording, "Record");
+                // Recording settings apply when recording starts
+                ui.add_enabled_ui(!settings.recording, |ui| {
+                    ui.label("every");
+                    ui.add(
+                        egui::DragValue::new(&mut settings.record_interval).clamp_range(1..=1000),
+                    );
+                    ui.label("steps to");
+                    ui.text_edit_singleline(&mut settings.record_dir);
+                });
+            });
+            ui.heading("Matter");
+            matter_palette(ui, &registry, &mut settings);
         });
 }
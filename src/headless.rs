@@ -0,0 +1,273 @@
+use std::{path::Path, sync::Arc, time::Instant};
+
+use vulkano::{
+    device::{
+        physical::PhysicalDeviceType, Device, DeviceCreateInfo, Queue, QueueCreateInfo, QueueFlags,
+    },
+    instance::{Instance, InstanceCreateInfo, InstanceExtensions},
+    memory::allocator::StandardMemoryAllocator,
+    VulkanLibrary,
+};
+
+use crate::{
+    ca_simulator::CASimulator,
+    cell::Cell,
+    cli::CliArgs,
+    cpu_simulator::{compare_with_gpu, random_grid, CpuSimulator},
+    matter::{MatterId, MatterRegistry, MATTER_DEFINITIONS_PATH},
+    png_image::{import_png, ColorMapping, COLOR_MAPPING_PATH},
+    rules::RuleMode,
+    save::CanvasSave,
+};
+
+/// Steps recorded into one command buffer when running headless
+const STEPS_PER_SUBMISSION: u64 = 64;
+
+/// Vulkan device with a compute queue, but no surface or swapchain. Works also with software
+/// drivers such as lavapipe.
+pub struct HeadlessContext {
+    pub device_name: String,
+    pub compute_queue: Arc<Queue>,
+    pub memory_allocator: Arc<StandardMemoryAllocator>,
+}
+
+impl HeadlessContext {
+    pub fn new() -> Result<HeadlessContext, String> {
+        let library =
+            VulkanLibrary::new().map_err(|e| format!("Failed to load Vulkan library: {}", e))?;
+        let instance = Instance::new(library, InstanceCreateInfo {
+            enabled_extensions: InstanceExtensions {
+                #[cfg(target_os = "macos")]
+                khr_portability_enumeration: true,
+                ..InstanceExtensions::empty()
+            },
+            #[cfg(target_os = "macos")]
+            enumerate_portability: true,
+            ..Default::default()
+        })
+        .map_err(|e| format!("Failed to create instance: {}", e))?;
+        // Prefer real gpus, but accept anything that can compute
+        let (physical_device, queue_family_index) = instance
+            .enumerate_physical_devices()
+            .map_err(|e| format!("Failed to enumerate physical devices: {}", e))?
+            .filter_map(|p| {
+                p.queue_family_properties()
+                    .iter()
+                    .position(|q| q.queue_flags.intersects(QueueFlags::COMPUTE))
+                    .map(|i| (p, i as u32))
+            })
+            .min_by_key(|(p, _)| match p.properties().device_type {
+                PhysicalDeviceType::DiscreteGpu => 0,
+                PhysicalDeviceType::IntegratedGpu => 1,
+                PhysicalDeviceType::VirtualGpu => 2,
+                PhysicalDeviceType::Cpu => 3,
+                _ => 4,
+            })
+            .ok_or_else(|| "No Vulkan device with compute support found".to_string())?;
+        let device_name = physical_device.properties().device_name.clone();
+        let (device, mut queues) = Device::new(physical_device, DeviceCreateInfo {
+            queue_create_infos: vec![QueueCreateInfo {
+                queue_family_index,
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .map_err(|e| format!("Failed to create device: {}", e))?;
+        let compute_queue = queues.next().unwrap();
+        let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device));
+        Ok(HeadlessContext {
+            device_name,
+            compute_queue,
+            memory_allocator,
+        })
+    }
+}
+
+/// Canvas to start a headless run from: a save as is, or a PNG image mapped to matters like the
+/// gui's import, starting at step 0 with the given seed
+fn load_canvas(path: &str, registry: &MatterRegistry, seed: u32) -> Result<CanvasSave, String> {
+    let is_png = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+    if !is_png {
+        let save = CanvasSave::load(path).and_then(|save| {
+            save.validate(registry)?;
+            Ok(save)
+        });
+        return save.map_err(|e| format!("Failed to load canvas from {}: {}", path, e));
+    }
+    let (canvas_size, matter) = ColorMapping::load(COLOR_MAPPING_PATH, registry)
+        .and_then(|mapping| import_png(path, &mapping))
+        .map_err(|e| format!("Failed to import {}: {}", path, e))?;
+    let properties = registry.properties();
+    Ok(CanvasSave {
+        canvas_size,
+        registry_version: registry.version(),
+        seed,
+        steps: 0,
+        cells: matter
+            .iter()
+            .map(|&m| Cell::of_matter(MatterId(m), &properties))
+            .collect(),
+    })
+}
+
+/// Run the simulation for `args.steps` steps without a window, from an empty canvas unless a
+/// save or image to load is given. A loaded canvas keeps its size.
+pub fn run(args: &CliArgs) -> Result<(), String> {
+    if args.verify && args.rules != RuleMode::FallingSand {
+        return Err(
+            "--verify supports only falling sand, the cpu reference implements no other rules"
+                .to_string(),
+        );
+    }
+    let context = HeadlessContext::new()?;
+    let matter_registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).map_err(|e| {
+        format!(
+            "Failed to load matters from {}: {}",
+            MATTER_DEFINITIONS_PATH, e
+        )
+    })?;
+    let loaded = match &args.load {
+        Some(path) => Some(load_canvas(path, &matter_registry, args.seed)?),
+        None => None,
+    };
+    let (canvas_size, seed) = loaded
+        .as_ref()
+        .map_or((args.canvas_size, args.seed), |save| {
+            (save.canvas_size, save.seed)
+        });
+    let mut simulator = CASimulator::new(
+        &context.memory_allocator,
+        context.compute_queue.clone(),
+        None,
+        &matter_registry,
+        &args.rules.rule_set(),
+        canvas_size,
+        seed,
+    );
+    if args.verify {
+        return verify(
+            &mut simulator,
+            &matter_registry,
+            loaded.as_ref(),
+            args.steps,
+        );
+    }
+    if let Some(save) = &loaded {
+        simulator.replace_cells(&save.cells);
+        simulator.set_steps(save.steps);
+    }
+    println!(
+        "Simulating {} steps on {}x{} canvas using {}",
+        args.steps, canvas_size.x, canvas_size.y, context.device_name
+    );
+    let start = Instant::now();
+    let mut remaining = args.steps;
+    while remaining > 0 {
+        let batch = remaining.min(STEPS_PER_SUBMISSION);
+        simulator.step_many(batch as u32);
+        remaining -= batch;
+    }
+    simulator.wait();
+    let elapsed = start.elapsed();
+    println!(
+        "Finished in {:.2?} ({:.1} steps/s)",
+        elapsed,
+        args.steps as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Run gpu & cpu simulations side by side from the loaded canvas's matter, or else a random grid,
+/// and report the first difference
+fn verify(
+    simulator: &mut CASimulator,
+    matter_registry: &MatterRegistry,
+    loaded: Option<&CanvasSave>,
+    steps: u64,
+) -> Result<(), String> {
+    let canvas_size = simulator.canvas_size();
+    let mut cpu = CpuSimulator::new(matter_registry, canvas_size, simulator.seed());
+    let initial = match loaded {
+        Some(save) => save.cells.iter().map(|cell| cell.matter).collect(),
+        None => {
+            let matter_count = matter_registry.matters().count() as u32;
+            random_grid(matter_count, canvas_size, 0.3, 42)
+        }
+    };
+    println!("Comparing {} steps against cpu reference", steps);
+    compare_with_gpu(simulator, &mut cpu, &initial, steps).map_err(|m| {
+        format!(
+            "Mismatch at step {} at {}: gpu {}, cpu {}",
+            m.step, m.pos, m.gpu, m.cpu
+        )
+    })?;
+    println!("Gpu and cpu simulations match");
+    Ok(())
+}
+
+/// Simulator of the default matters for gpu tests. These are ignored by default, because they
+/// need a Vulkan device, e.g. lavapipe on machines without a gpu: run them with
+/// `cargo test -- --ignored`.
+#[cfg(test)]
+pub fn test_simulator(
+    canvas_size: bevy::math::UVec2,
+    rules: &crate::rules::RuleSet,
+) -> CASimulator {
+    let context = HeadlessContext::new().expect("gpu tests need a Vulkan device");
+    let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+    CASimulator::new(
+        &context.memory_allocator,
+        context.compute_queue,
+        None,
+        &registry,
+        rules,
+        canvas_size,
+        0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::UVec2;
+
+    use super::*;
+    use crate::png_image::export_png;
+
+    #[test]
+    fn loads_saves_and_images() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let sand = registry.id("sand").unwrap();
+        let mut cell = Cell::of_matter(sand, &registry.properties());
+        cell.state = 0.5;
+        let save = CanvasSave {
+            canvas_size: UVec2::new(2, 1),
+            registry_version: registry.version(),
+            seed: 7,
+            steps: 100,
+            cells: vec![Cell::EMPTY, cell],
+        };
+        let save_path = std::env::temp_dir().join("cellular_automata_headless_test.cas");
+        save.save(&save_path).unwrap();
+        let loaded = load_canvas(save_path.to_str().unwrap(), &registry, 0);
+        std::fs::remove_file(&save_path).unwrap();
+        assert_eq!(loaded, Ok(save.clone()));
+
+        // Images start over at step 0 with the given seed
+        let png_path = std::env::temp_dir().join("cellular_automata_headless_test.PNG");
+        export_png(&png_path, save.canvas_size, &save.cells, |cell| {
+            registry.get(MatterId(cell.matter)).unwrap().color
+        })
+        .unwrap();
+        let loaded = load_canvas(png_path.to_str().unwrap(), &registry, 3).unwrap();
+        std::fs::remove_file(&png_path).unwrap();
+        assert_eq!((loaded.seed, loaded.steps), (3, 0));
+        assert_eq!(loaded.cells, vec![
+            Cell::EMPTY,
+            Cell::of_matter(sand, &registry.properties())
+        ]);
+
+        assert!(load_canvas("missing.cas", &registry, 0).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::Resource;
+use vulkano::buffer::BufferContents;
+
+/// Cells remembered in undo history at most. The latest stroke is always kept however large.
+pub const MAX_HISTORY_CELLS: usize = 1 << 21;
+
+/// Cell changed by drawing from matter `before` to `after`, as laid out in brush.glsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, BufferContents)]
+pub struct CellChange {
+    pub index: u32,
+    pub before: u32,
+    pub after: u32,
+}
+
+/// Cells touched by a stroke, from press to release: index to the value before the stroke and
+/// the value drawn last
+#[derive(Debug, Default)]
+struct Stroke {
+    cells: HashMap<u32, (u32, u32)>,
+}
+
+impl Stroke {
+    /// Changes redoing the stroke, or undoing it if `undo`
+    fn changes(&self, undo: bool) -> Vec<CellChange> {
+        self.cells
+            .iter()
+            .map(|(&index, &(before, after))| {
+                let (before, after) = if undo {
+                    (after, before)
+                } else {
+                    (before, after)
+                };
+                CellChange {
+                    index,
+                    before,
+                    after,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Undo & redo of drawing. Only the cells a stroke touched are restored, and only those still
+/// holding what the stroke left, so that undoing while the simulation runs leaves matter that
+/// moved since as it is.
+#[derive(Debug, Resource)]
+pub struct History {
+    undo: VecDeque<Stroke>,
+    redo: Vec<Stroke>,
+    stroke: Option<Stroke>,
+    max_cells: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(MAX_HISTORY_CELLS)
+    }
+}
+
+impl History {
+    pub fn new(max_cells: usize) -> History {
+        History {
+            undo: VecDeque::new(),
+            redo: vec![],
+            stroke: None,
+            max_cells,
+        }
+    }
+
+    /// Add changes to the current stroke, starting one if needed
+    pub fn record(&mut self, changes: &[CellChange]) {
+        let stroke = self.stroke.get_or_insert_with(Stroke::default);
+        for change in changes {
+            stroke
+                .cells
+                .entry(change.index)
+                .and_modify(|(_, after)| *after = change.after)
+                .or_insert((change.before, change.after));
+        }
+    }
+
+    /// Finish the current stroke, which becomes undoable and discards redoable strokes
+    pub fn end_stroke(&mut self) {
+        let stroke = match self.stroke.take() {
+            Some(stroke) if !stroke.cells.is_empty() => stroke,
+            _ => return,
+        };
+        self.redo.clear();
+        self.undo.push_back(stroke);
+        let mut cells: usize = self.undo.iter().map(|stroke| stroke.cells.len()).sum();
+        while cells > self.max_cells && self.undo.len() > 1 {
+            cells -= self.undo.pop_front().unwrap().cells.len();
+        }
+    }
+
+    /// Changes undoing the last stroke, to apply with
+    /// [`CASimulator::apply_changes`](crate::ca_simulator::CASimulator::apply_changes)
+    pub fn undo(&mut self) -> Option<Vec<CellChange>> {
+        self.end_stroke();
+        let stroke = self.undo.pop_back()?;
+        let changes = stroke.changes(true);
+        self.redo.push(stroke);
+        Some(changes)
+    }
+
+    /// Changes redoing the last undone stroke, to apply with
+    /// [`CASimulator::apply_changes`](crate::ca_simulator::CASimulator::apply_changes)
+    pub fn redo(&mut self) -> Option<Vec<CellChange>> {
+        self.end_stroke();
+        let stroke = self.redo.pop()?;
+        let changes = stroke.changes(false);
+        self.undo.push_back(stroke);
+        Some(changes)
+    }
+
+    /// Forget everything, e.g. when the canvas is replaced
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.stroke = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::{IVec2, UVec2};
+
+    use super::*;
+    use crate::{headless::test_simulator, matter::MatterId, rules::RuleSet};
+
+    fn change(index: u32, before: u32, after: u32) -> CellChange {
+        CellChange {
+            index,
+            before,
+            after,
+        }
+    }
+
+    fn sorted(mut changes: Vec<CellChange>) -> Vec<CellChange> {
+        changes.sort_by_key(|change| change.index);
+        changes
+    }
+
+    #[test]
+    fn undo_restores_cells_before_stroke() {
+        let mut history = History::default();
+        history.record(&[change(1, 0, 5), change(2, 3, 5)]);
+        // Same cell drawn again within the stroke
+        history.record(&[change(1, 5, 6)]);
+        history.end_stroke();
+        // Undoing expects the matter drawn last, redoing the matter before the stroke
+        assert_eq!(sorted(history.undo().unwrap()), vec![
+            change(1, 6, 0),
+            change(2, 5, 3)
+        ]);
+        assert_eq!(sorted(history.redo().unwrap()), vec![
+            change(1, 0, 6),
+            change(2, 3, 5)
+        ]);
+        assert!(history.redo().is_none());
+    }
+
+    #[test]
+    fn new_stroke_discards_redo() {
+        let mut history = History::default();
+        history.record(&[change(1, 0, 5)]);
+        history.end_stroke();
+        history.undo().unwrap();
+        history.record(&[change(2, 0, 5)]);
+        history.end_stroke();
+        assert!(history.redo().is_none());
+        assert_eq!(history.undo().unwrap(), vec![change(2, 5, 0)]);
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    fn memory_is_bounded() {
+        let mut history = History::new(3);
+        for i in 0..3 {
+            history.record(&[change(i * 2, 0, 1), change(i * 2 + 1, 0, 1)]);
+            history.end_stroke();
+        }
+        // Only the last stroke fits within the limit
+        assert!(history.undo().is_some());
+        assert!(history.undo().is_none());
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_undo_keeps_cells_changed_since() {
+        let mut simulator = test_simulator(UVec2::new(3, 1), &RuleSet::falling_sand());
+        let (sand, water) = (MatterId(1), MatterId(2));
+        let mut history = History::default();
+        let cells = [IVec2::new(0, 0), IVec2::new(1, 0), IVec2::new(2, 0)];
+        history.record(&simulator.draw_matter(&cells, sand, None));
+        history.end_stroke();
+        // Water took the middle cell's place after the stroke
+        let mut grid = simulator.matter();
+        grid[1] = water.0;
+        simulator.set_matter(&grid);
+        simulator.apply_changes(&history.undo().unwrap());
+        assert_eq!(simulator.matter(), vec![0, water.0, 0]);
+        // Redoing likewise leaves the water
+        simulator.apply_changes(&history.redo().unwrap());
+        assert_eq!(simulator.matter(), vec![sand.0, water.0, sand.0]);
+    }
+}
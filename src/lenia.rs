@@ -0,0 +1,207 @@
+use std::sync::Arc;
+
+use bevy::math::{IVec2, UVec2};
+use strum_macros::{Display, EnumIter};
+use vulkano::{
+    descriptor_set::layout::DescriptorSetLayoutBinding, device::Device, shader::ShaderModule,
+};
+
+use crate::{
+    rules::{rule_descriptor_layout, CellularRule, KernelConstants, RULE_BUFFER_BINDING},
+    utils::storage_buffer_desc,
+};
+
+/// Kernel radius range. Smaller kernels have no cells strictly inside the ring.
+pub const MIN_LENIA_RADIUS: u32 = 2;
+pub const MAX_LENIA_RADIUS: u32 = 64;
+
+/// Growth of a cell by its potential, peaking at `mu` with width `sigma`. Must match the
+/// `GROWTH_*` defines in lenia.glsl.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display, EnumIter)]
+pub enum GrowthFunction {
+    /// Smooth bell curve of the original Lenia
+    Gaussian = 0,
+    /// Bell curve with finite support
+    Polynomial = 1,
+    /// Growth within `sigma` of `mu`, decay elsewhere, as in SmoothLife
+    Step = 2,
+}
+
+/// Lenia-style continuous automaton of the cells' states 0.0 - 1.0, leaving their matter as is
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LeniaRule {
+    /// Kernel radius in cells
+    pub radius: u32,
+    pub mu: f32,
+    pub sigma: f32,
+    /// Time step
+    pub dt: f32,
+    pub growth: GrowthFunction,
+}
+
+impl Default for LeniaRule {
+    /// Parameters of Orbium, the best known Lenia glider
+    fn default() -> Self {
+        LeniaRule {
+            radius: 13,
+            mu: 0.15,
+            sigma: 0.015,
+            dt: 0.1,
+            growth: GrowthFunction::Gaussian,
+        }
+    }
+}
+
+impl LeniaRule {
+    /// Kernel radius within the supported range
+    fn kernel_radius(&self) -> u32 {
+        self.radius.clamp(MIN_LENIA_RADIUS, MAX_LENIA_RADIUS)
+    }
+
+    /// Weights of the ring shaped kernel, an exponential bump peaking halfway out and zero at the
+    /// center & beyond the radius. Rows of offsets from -radius to radius, normalized to sum up to
+    /// 1.0 so that the potential is a weighted average.
+    pub fn kernel_weights(&self) -> Vec<f32> {
+        let radius = self.kernel_radius() as i32;
+        let weights: Vec<f32> = (-radius..=radius)
+            .flat_map(|y| (-radius..=radius).map(move |x| IVec2::new(x, y)))
+            .map(|offset| {
+                let r = offset.as_vec2().length() / radius as f32;
+                if r <= 0.0 || r >= 1.0 {
+                    0.0
+                } else {
+                    (4.0 - 1.0 / (r * (1.0 - r))).exp()
+                }
+            })
+            .collect();
+        let total: f32 = weights.iter().sum();
+        weights.into_iter().map(|weight| weight / total).collect()
+    }
+}
+
+impl CellularRule for LeniaRule {
+    fn name(&self) -> &str {
+        "lenia"
+    }
+
+    fn shader(&self, device: Arc<Device>) -> Arc<ShaderModule> {
+        lenia_cs::load(device).unwrap()
+    }
+
+    /// The kernel's weights are computed once per rule, instead of for every cell & step
+    fn descriptor_layout(&self) -> Vec<(u32, DescriptorSetLayoutBinding)> {
+        let mut layout = rule_descriptor_layout();
+        layout.push((RULE_BUFFER_BINDING, storage_buffer_desc()));
+        layout
+    }
+
+    fn rule_buffer(&self) -> Option<Vec<f32>> {
+        Some(self.kernel_weights())
+    }
+
+    /// The rule's parameters are specialization constants of its own
+    fn specialization_constants(&self, canvas_size: UVec2) -> KernelConstants {
+        KernelConstants::new(canvas_size)
+            .with(5, self.kernel_radius() as i32)
+            .with(6, self.mu)
+            .with(7, self.sigma)
+            .with(8, self.dt)
+            .with(9, self.growth as u32)
+    }
+}
+
+/// RGBA color of a cell's state as colored by lenia_color.glsl
+pub fn colormap(state: f32) -> u32 {
+    const COEFFICIENTS: [[f32; 3]; 7] = [
+        [0.277_727_33, 0.005_407_344_5, 0.334_099_8],
+        [0.105_093_04, 1.404_613_5, 1.384_590_2],
+        [-0.330_861_83, 0.214_847_56, 0.095_095_16],
+        [-4.634_230_4, -5.799_101, -19.332_441],
+        [6.228_27, 14.179_933, 56.690_55],
+        [4.776_385, -13.745_145, -65.353_035],
+        [-5.435_456, 4.645_852_6, 26.312_435],
+    ];
+    let t = state.clamp(0.0, 1.0);
+    let [r, g, b] = [0, 1, 2].map(|channel| {
+        let value = COEFFICIENTS
+            .iter()
+            .rev()
+            .fold(0.0, |acc, c| acc * t + c[channel]);
+        (value.clamp(0.0, 1.0) * 255.0).round() as u32
+    });
+    r << 24 | g << 16 | b << 8 | 255
+}
+
+mod lenia_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/lenia.glsl"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ca_simulator::CASimulator, cell::Cell, headless::test_simulator, matter::MatterId,
+        rules::RuleSet,
+    };
+
+    #[test]
+    fn colormap_spans_viridis() {
+        // Dark purple to yellow
+        assert_eq!(colormap(0.0), 0x47_01_55_ff);
+        assert_eq!(colormap(1.0), 0xfc_e7_21_ff);
+    }
+
+    #[test]
+    fn kernel_weights_are_normalized_rings() {
+        for radius in [MIN_LENIA_RADIUS, 13, MAX_LENIA_RADIUS] {
+            let rule = LeniaRule {
+                radius,
+                ..LeniaRule::default()
+            };
+            let weights = rule.kernel_weights();
+            let side = 2 * radius as usize + 1;
+            assert_eq!(weights.len(), side * side);
+            assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+            // Nothing at the center, symmetric around it
+            assert_eq!(weights[weights.len() / 2], 0.0);
+            assert!(weights.iter().eq(weights.iter().rev()));
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_states_stay_in_unit_range() {
+        let size = UVec2::new(40, 30);
+        let mut simulator = test_simulator(size, &RuleSet::lenia(LeniaRule::default()));
+        let states = |simulator: &CASimulator| -> Vec<f32> {
+            simulator.cells().iter().map(|cell| cell.state).collect()
+        };
+        // Nothing grows from nothing
+        simulator.step_many(5);
+        assert!(states(&simulator).iter().all(|&state| state == 0.0));
+        // A blob changes but stays within range
+        let blob: Vec<Cell> = (0..size.x * size.y)
+            .map(|i| {
+                let (x, y) = ((i % size.x) as f32 - 20.0, (i / size.x) as f32 - 15.0);
+                Cell {
+                    state: (1.0 - (x * x + y * y).sqrt() / 10.0).max(0.0),
+                    ..Cell::EMPTY
+                }
+            })
+            .collect();
+        simulator.replace_cells(&blob);
+        simulator.step_many(10);
+        let after = states(&simulator);
+        assert_ne!(
+            after,
+            blob.iter().map(|cell| cell.state).collect::<Vec<_>>()
+        );
+        assert!(after.iter().all(|state| (0.0..=1.0).contains(state)));
+        // Matter stays as is
+        assert!(simulator.matter().iter().all(|&m| m == MatterId::EMPTY.0));
+    }
+}
@@ -0,0 +1,162 @@
+use std::{fmt, sync::Arc};
+
+use bevy::math::UVec2;
+use vulkano::{device::Device, shader::ShaderModule};
+
+use crate::rules::{CellularRule, KernelConstants};
+
+/// Well known Life-like rules, by name and rule string
+pub const LIFE_PRESETS: [(&str, &str); 4] = [
+    ("Life", "B3/S23"),
+    ("HighLife", "B36/S23"),
+    ("Seeds", "B2/S"),
+    ("Day & Night", "B3678/S34678"),
+];
+
+/// Errors from parsing rule strings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifeRuleError {
+    /// Not of the form `B<counts>/S<counts>`
+    InvalidFormat,
+    /// Neighbor count other than 0-8
+    InvalidCount(char),
+    /// Birth on zero neighbors would fill the whole empty canvas every other step
+    BirthOnZero,
+}
+
+impl fmt::Display for LifeRuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LifeRuleError::InvalidFormat => write!(f, "expected a rule like B3/S23"),
+            LifeRuleError::InvalidCount(c) => write!(f, "invalid neighbor count {}", c),
+            LifeRuleError::BirthOnZero => write!(f, "birth on 0 neighbors is not supported"),
+        }
+    }
+}
+
+impl std::error::Error for LifeRuleError {}
+
+/// Life-like automaton: empty cells with a birth count of living neighbors are born, living cells
+/// with a survive count survive, the others die. Any matter is alive, newborn cells take the most
+/// common matter of their neighbors. Counts are bit masks, bit n standing for n neighbors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LifeRule {
+    pub birth: u32,
+    pub survive: u32,
+}
+
+impl LifeRule {
+    /// Conway's Game of Life, B3/S23
+    pub const CONWAY: LifeRule = LifeRule {
+        birth: 1 << 3,
+        survive: 1 << 2 | 1 << 3,
+    };
+
+    /// Parse rule string in B/S notation, e.g. `B3/S23`, case insensitive
+    pub fn parse(rule: &str) -> Result<LifeRule, LifeRuleError> {
+        let rule = rule.trim().to_ascii_uppercase();
+        let (birth, survive) = rule.split_once('/').ok_or(LifeRuleError::InvalidFormat)?;
+        let counts = |counts: &str, prefix: char| -> Result<u32, LifeRuleError> {
+            let counts = counts
+                .strip_prefix(prefix)
+                .ok_or(LifeRuleError::InvalidFormat)?;
+            counts.chars().try_fold(0, |mask, c| match c.to_digit(10) {
+                Some(n) if n <= 8 => Ok(mask | 1 << n),
+                _ => Err(LifeRuleError::InvalidCount(c)),
+            })
+        };
+        let rule = LifeRule {
+            birth: counts(birth, 'B')?,
+            survive: counts(survive, 'S')?,
+        };
+        if rule.birth & 1 != 0 {
+            return Err(LifeRuleError::BirthOnZero);
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for LifeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = |mask: u32| -> String {
+            (0..=8)
+                .filter(|n| mask & 1 << n != 0)
+                .map(|n| n.to_string())
+                .collect()
+        };
+        write!(f, "B{}/S{}", counts(self.birth), counts(self.survive))
+    }
+}
+
+impl CellularRule for LifeRule {
+    fn name(&self) -> &str {
+        "life"
+    }
+
+    fn shader(&self, device: Arc<Device>) -> Arc<ShaderModule> {
+        life_cs::load(device).unwrap()
+    }
+
+    /// The rule's counts are specialization constants of its own
+    fn specialization_constants(&self, canvas_size: UVec2) -> KernelConstants {
+        KernelConstants::new(canvas_size)
+            .with(5, self.birth)
+            .with(6, self.survive)
+    }
+}
+
+mod life_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/life.glsl"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{headless::test_simulator, matter::MatterId, rules::RuleSet};
+
+    #[test]
+    fn parses_rule_strings() {
+        assert_eq!(LifeRule::parse("B3/S23"), Ok(LifeRule::CONWAY));
+        assert_eq!(LifeRule::parse(" b3/s32 "), Ok(LifeRule::CONWAY));
+        for (_, rule) in LIFE_PRESETS {
+            assert_eq!(LifeRule::parse(rule).unwrap().to_string(), rule);
+        }
+        assert_eq!(LifeRule::parse("3/23"), Err(LifeRuleError::InvalidFormat));
+        assert_eq!(
+            LifeRule::parse("B39/S23"),
+            Err(LifeRuleError::InvalidCount('9'))
+        );
+        assert_eq!(LifeRule::parse("B03/S23"), Err(LifeRuleError::BirthOnZero));
+    }
+
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_blinker_oscillates() {
+        let mut simulator = test_simulator(UVec2::new(5, 5), &RuleSet::life(LifeRule::CONWAY));
+        let sand = MatterId(1).0;
+        #[rustfmt::skip]
+        let horizontal = [
+            0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0,
+            0, sand, sand, sand, 0,
+            0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0,
+        ];
+        #[rustfmt::skip]
+        let vertical = [
+            0, 0, 0, 0, 0,
+            0, 0, sand, 0, 0,
+            0, 0, sand, 0, 0,
+            0, 0, sand, 0, 0,
+            0, 0, 0, 0, 0,
+        ];
+        simulator.set_matter(&horizontal);
+        simulator.step();
+        assert_eq!(simulator.matter(), vertical);
+        simulator.step();
+        assert_eq!(simulator.matter(), horizontal);
+    }
+}
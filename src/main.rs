@@ -1,9 +1,24 @@
+mod brush;
 mod ca_simulator;
 mod camera;
+mod cell;
+mod cli;
+mod cpu_simulator;
+mod double_buffer;
 mod gui;
+mod headless;
+mod history;
+mod lenia;
+mod life;
+mod matter;
+mod png_image;
 mod quad_pipeline;
+mod recorder;
 #[allow(clippy::too_many_arguments)]
 mod render;
+mod rules;
+mod save;
+mod sim_clock;
 mod utils;
 mod vertex;
 
@@ -16,41 +31,136 @@ use bevy::{
 use bevy_vulkano::{
     BevyVulkanoContext, BevyVulkanoSettings, BevyVulkanoWindows, VulkanoWinitPlugin,
 };
+use vulkano::sync::GpuFuture;
+use vulkano_util::renderer::VulkanoWindowRenderer;
 
 use crate::{
+    brush::{flood_fill, BrushStroke, BrushTool, DEFAULT_SPRAY_DENSITY},
     ca_simulator::CASimulator,
     camera::OrthographicCamera,
-    gui::user_interface,
+    cli::{CliArgs, USAGE},
+    gui::{drag_preview, user_interface},
+    history::History,
+    lenia::LeniaRule,
+    life::LifeRule,
+    matter::{MatterId, MatterRegistry, MATTER_DEFINITIONS_PATH},
+    png_image::{
+        export_png, import_png, ColorMapping, COLOR_MAPPING_PATH, DEFAULT_IMAGE_PATH,
+        SCREENSHOT_DIR,
+    },
+    recorder::{Recorder, DEFAULT_RECORD_DIR, DEFAULT_RECORD_INTERVAL},
     render::FillScreenRenderPass,
-    utils::{cursor_to_world, get_canvas_line, MousePos},
+    rules::{RuleMode, RuleSet},
+    save::{CanvasSave, DEFAULT_SAVE_PATH},
+    sim_clock::SimClock,
+    utils::{cursor_to_world, get_canvas_segment, MousePos},
 };
 
 pub const WIDTH: f32 = 1920.0;
 pub const HEIGHT: f32 = 1080.0;
-pub const CANVAS_SIZE_X: u32 = 512;
-pub const CANVAS_SIZE_Y: u32 = 512;
+pub const DEFAULT_CANVAS_SIZE: UVec2 = UVec2::new(512, 512);
+pub const MAX_CANVAS_SIZE: u32 = 8192;
+pub const DEFAULT_SEED: u32 = 0;
 pub const LOCAL_SIZE_X: u32 = 32;
 pub const LOCAL_SIZE_Y: u32 = 32;
-pub const NUM_WORK_GROUPS_X: u32 = CANVAS_SIZE_X / LOCAL_SIZE_X;
-pub const NUM_WORK_GROUPS_Y: u32 = CANVAS_SIZE_Y / LOCAL_SIZE_Y;
 pub const CLEAR_COLOR: [f32; 4] = [1.0; 4];
 pub const CAMERA_MOVE_SPEED: f32 = 200.0;
+/// Keys selecting matter by id, 0 being the empty matter (erase)
+pub const MATTER_SHORTCUTS: [KeyCode; 10] = [
+    KeyCode::Key0,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+];
 
 #[derive(Resource)]
 pub struct DynamicSettings {
     pub brush_radius: f32,
-    pub draw_matter: u32,
+    pub brush_tool: BrushTool,
+    /// Share of cells the spray covers
+    pub spray_density: f32,
+    pub draw_matter: MatterId,
+    /// Draw only over this matter, e.g. to recolor rock without touching the sand on it
+    pub replace_only: Option<MatterId>,
+    /// Canvas size used when the canvas is (re)created
+    pub canvas_size: UVec2,
+    /// Seed used when the canvas is (re)created
+    pub seed: u32,
+    /// Rules simulated
+    pub rules: RuleMode,
+    /// Life-like rule string being edited in the gui
+    pub life_rule: String,
+    /// Lenia parameters being edited in the gui
+    pub lenia: LeniaRule,
+    /// File used by save & load
+    pub save_path: String,
+    /// PNG imported as matter layout
+    pub image_path: String,
+    /// Record frames every `record_interval` steps to `record_dir`
+    pub recording: bool,
+    pub record_interval: u64,
+    pub record_dir: String,
+    /// Show the fields of the cell under the mouse
+    pub inspect_cell: bool,
 }
 
 impl Default for DynamicSettings {
     fn default() -> Self {
         Self {
             brush_radius: 4.0,
-            draw_matter: 0xff0000ff,
+            brush_tool: BrushTool::Round,
+            spray_density: DEFAULT_SPRAY_DENSITY,
+            // First matter after empty matter
+            draw_matter: MatterId(1),
+            replace_only: None,
+            canvas_size: DEFAULT_CANVAS_SIZE,
+            seed: DEFAULT_SEED,
+            rules: RuleMode::default(),
+            life_rule: LifeRule::CONWAY.to_string(),
+            lenia: LeniaRule::default(),
+            save_path: DEFAULT_SAVE_PATH.to_string(),
+            image_path: DEFAULT_IMAGE_PATH.to_string(),
+            recording: false,
+            record_interval: DEFAULT_RECORD_INTERVAL,
+            record_dir: DEFAULT_RECORD_DIR.to_string(),
+            inspect_cell: false,
         }
     }
 }
 
+/// Event to recreate an empty canvas with the size in [`DynamicSettings`]
+pub struct RecreateCanvas;
+
+/// Event to simulate the rules in [`DynamicSettings`], keeping the canvas
+pub struct ChangeRules {
+    /// Rules simulated until now
+    pub previous: RuleMode,
+}
+
+/// Event to save the canvas to the save path in [`DynamicSettings`]
+pub struct SaveCanvas;
+
+/// Event to load the canvas from the save path in [`DynamicSettings`]
+pub struct LoadCanvas;
+
+/// Event to import the image in [`DynamicSettings`] as the canvas
+pub struct ImportImage;
+
+/// Event to undo the last brush stroke
+pub struct UndoStroke;
+
+/// Event to redo the last undone brush stroke
+pub struct RedoStroke;
+
+/// Event to save a PNG of the canvas to [`SCREENSHOT_DIR`]
+pub struct TakeScreenshot;
+
 pub struct PluginBundle;
 impl PluginGroup for PluginBundle {
     fn build(self) -> PluginGroupBuilder {
@@ -71,6 +181,20 @@ impl PluginGroup for PluginBundle {
 }
 
 fn main() {
+    let args = match CliArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+    if args.headless {
+        if let Err(e) = headless::run(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     App::new()
         .insert_non_send_resource(BevyVulkanoSettings {
             // Since we're only drawing gui, let's clear each frame
@@ -88,15 +212,49 @@ fn main() {
             }),
             ..default()
         }))
+        .insert_resource(DynamicSettings {
+            canvas_size: args.canvas_size,
+            seed: args.seed,
+            rules: args.rules,
+            life_rule: match args.rules {
+                RuleMode::Life(rule) => rule.to_string(),
+                _ => LifeRule::CONWAY.to_string(),
+            },
+            lenia: match args.rules {
+                RuleMode::Lenia(rule) => rule,
+                _ => LeniaRule::default(),
+            },
+            ..default()
+        })
+        .init_resource::<SimClock>()
+        .init_resource::<History>()
+        .add_event::<RecreateCanvas>()
+        .add_event::<ChangeRules>()
+        .add_event::<SaveCanvas>()
+        .add_event::<LoadCanvas>()
+        .add_event::<ImportImage>()
+        .add_event::<TakeScreenshot>()
+        .add_event::<UndoStroke>()
+        .add_event::<RedoStroke>()
         .add_startup_system(setup)
         .add_system(close_on_esc)
         .add_system(input_actions)
+        .add_system(shortcuts)
         .add_system(update_camera)
         .add_system(update_mouse)
         .add_system(draw_matter)
+        .add_system(undo_redo.after(draw_matter))
+        .add_system(recreate_canvas)
+        .add_system(change_rules)
+        .add_system(save_canvas)
+        .add_system(load_canvas)
+        .add_system(import_image)
         .add_system(simulate)
+        .add_system(take_screenshot.after(simulate))
+        .add_system(record_frames.after(simulate))
         // Gui
         .add_system(user_interface)
+        .add_system(drag_preview)
         // Render after update
         .add_system(render.in_base_set(CoreSet::PostUpdate))
         .run();
@@ -108,6 +266,7 @@ fn setup(
     window_query: Query<Entity, With<Window>>,
     context: Res<BevyVulkanoContext>,
     windows: NonSend<BevyVulkanoWindows>,
+    settings: Res<DynamicSettings>,
 ) {
     let window_entity = window_query.single();
     let primary_window = windows.get_vulkano_window(window_entity).unwrap();
@@ -118,27 +277,286 @@ fn setup(
         primary_window.renderer.graphics_queue(),
         primary_window.renderer.swapchain_format(),
     );
+    let matter_registry = match MatterRegistry::load(MATTER_DEFINITIONS_PATH) {
+        Ok(registry) => registry,
+        Err(e) => panic!(
+            "Failed to load matters from {}: {}",
+            MATTER_DEFINITIONS_PATH, e
+        ),
+    };
     let simulator = CASimulator::new(
         context.context.memory_allocator(),
         primary_window.renderer.compute_queue(),
+        Some(
+            primary_window
+                .renderer
+                .graphics_queue()
+                .queue_family_index(),
+        ),
+        &matter_registry,
+        &settings.rules.rule_set(),
+        settings.canvas_size,
+        settings.seed,
     );
 
     // Create simple orthographic camera
     let mut camera = OrthographicCamera::default();
     // Zoom camera to fit vertical pixels
-    camera.zoom_to_fit_vertical_pixels(CANVAS_SIZE_Y, HEIGHT as u32);
+    camera.zoom_to_fit_vertical_pixels(settings.canvas_size.y, HEIGHT as u32);
     // Insert resources
     commands.insert_resource(fill_screen);
     commands.insert_resource(camera);
     commands.insert_resource(simulator);
+    commands.insert_resource(matter_registry);
     commands.insert_resource(PreviousMousePos(None));
+    commands.insert_resource(DragStart(None));
     commands.insert_resource(CurrentMousePos(None));
-    commands.insert_resource(DynamicSettings::default());
 }
 
-/// Step simulation
-fn simulate(mut sim_pipeline: ResMut<CASimulator>) {
-    sim_pipeline.step();
+/// Recreate simulator with an empty canvas when requested
+#[allow(clippy::too_many_arguments)]
+fn recreate_canvas(
+    mut events: EventReader<RecreateCanvas>,
+    mut simulator: ResMut<CASimulator>,
+    mut history: ResMut<History>,
+    mut camera: ResMut<OrthographicCamera>,
+    settings: Res<DynamicSettings>,
+    registry: Res<MatterRegistry>,
+    window_query: Query<Entity, With<PrimaryWindow>>,
+    context: Res<BevyVulkanoContext>,
+    windows: NonSend<BevyVulkanoWindows>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    let primary_window = windows.get_vulkano_window(window_query.single()).unwrap();
+    replace_simulator(
+        &mut simulator,
+        &mut camera,
+        &registry,
+        &context,
+        &primary_window.renderer,
+        &settings.rules.rule_set(),
+        settings.canvas_size,
+        settings.seed,
+    );
+    history.clear();
+}
+
+/// Simulate the rules in the settings when requested, keeping the canvas. Switching to Lenia
+/// starts its states over from the matter on the canvas.
+fn change_rules(
+    mut events: EventReader<ChangeRules>,
+    mut simulator: ResMut<CASimulator>,
+    settings: Res<DynamicSettings>,
+) {
+    // Rules before the first of this frame's changes
+    let Some(previous) = events
+        .iter()
+        .fold(None, |previous, event| previous.or(Some(event.previous)))
+    else {
+        return;
+    };
+    simulator.set_rules(&settings.rules.rule_set());
+    if !matches!(previous, RuleMode::Lenia(_)) && matches!(settings.rules, RuleMode::Lenia(_)) {
+        simulator.reset_states();
+    }
+}
+
+/// Replace simulator with one of an empty canvas and fit camera to it
+#[allow(clippy::too_many_arguments)]
+fn replace_simulator(
+    simulator: &mut CASimulator,
+    camera: &mut OrthographicCamera,
+    registry: &MatterRegistry,
+    context: &BevyVulkanoContext,
+    renderer: &VulkanoWindowRenderer,
+    rules: &RuleSet,
+    canvas_size: UVec2,
+    seed: u32,
+) {
+    *simulator = CASimulator::new(
+        context.context.memory_allocator(),
+        renderer.compute_queue(),
+        Some(renderer.graphics_queue().queue_family_index()),
+        registry,
+        rules,
+        canvas_size,
+        seed,
+    );
+    camera.zoom_to_fit_vertical_pixels(canvas_size.y, HEIGHT as u32);
+}
+
+/// Save canvas with its seed and step counter when requested
+fn save_canvas(
+    mut events: EventReader<SaveCanvas>,
+    simulator: Res<CASimulator>,
+    settings: Res<DynamicSettings>,
+    registry: Res<MatterRegistry>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    let save = CanvasSave {
+        canvas_size: simulator.canvas_size(),
+        registry_version: registry.version(),
+        seed: simulator.seed(),
+        steps: simulator.steps(),
+        cells: simulator.cells(),
+    };
+    // Saves must load again, thus hold only matter of the registry
+    match save
+        .validate(&registry)
+        .and_then(|()| save.save(&settings.save_path))
+    {
+        Ok(()) => info!("Saved canvas to {}", settings.save_path),
+        Err(e) => error!("Failed to save canvas to {}: {}", settings.save_path, e),
+    }
+}
+
+/// Load canvas when requested, recreating the simulator if the saved canvas size differs
+#[allow(clippy::too_many_arguments)]
+fn load_canvas(
+    mut events: EventReader<LoadCanvas>,
+    mut simulator: ResMut<CASimulator>,
+    mut history: ResMut<History>,
+    mut camera: ResMut<OrthographicCamera>,
+    mut settings: ResMut<DynamicSettings>,
+    registry: Res<MatterRegistry>,
+    window_query: Query<Entity, With<PrimaryWindow>>,
+    context: Res<BevyVulkanoContext>,
+    windows: NonSend<BevyVulkanoWindows>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    let save = match CanvasSave::load(&settings.save_path).and_then(|save| {
+        save.validate(&registry)?;
+        Ok(save)
+    }) {
+        Ok(save) => save,
+        Err(e) => {
+            error!("Failed to load canvas from {}: {}", settings.save_path, e);
+            return;
+        }
+    };
+    if save.canvas_size != simulator.canvas_size() {
+        let primary_window = windows.get_vulkano_window(window_query.single()).unwrap();
+        replace_simulator(
+            &mut simulator,
+            &mut camera,
+            &registry,
+            &context,
+            &primary_window.renderer,
+            &settings.rules.rule_set(),
+            save.canvas_size,
+            save.seed,
+        );
+    }
+    settings.canvas_size = save.canvas_size;
+    settings.seed = save.seed;
+    simulator.replace_cells(&save.cells);
+    simulator.set_seed(save.seed);
+    simulator.set_steps(save.steps);
+    history.clear();
+    info!("Loaded canvas from {}", settings.save_path);
+}
+
+/// Import image as canvas when requested, mapping pixel colors to matters. The canvas takes the
+/// size of the image.
+#[allow(clippy::too_many_arguments)]
+fn import_image(
+    mut events: EventReader<ImportImage>,
+    mut simulator: ResMut<CASimulator>,
+    mut history: ResMut<History>,
+    mut camera: ResMut<OrthographicCamera>,
+    mut settings: ResMut<DynamicSettings>,
+    registry: Res<MatterRegistry>,
+    window_query: Query<Entity, With<PrimaryWindow>>,
+    context: Res<BevyVulkanoContext>,
+    windows: NonSend<BevyVulkanoWindows>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    // Reload mapping each time so that it can be tweaked while the app runs
+    let imported = ColorMapping::load(COLOR_MAPPING_PATH, &registry)
+        .and_then(|mapping| import_png(&settings.image_path, &mapping));
+    let (canvas_size, matter) = match imported {
+        Ok(imported) => imported,
+        Err(e) => {
+            error!("Failed to import {}: {}", settings.image_path, e);
+            return;
+        }
+    };
+    if canvas_size != simulator.canvas_size() {
+        let primary_window = windows.get_vulkano_window(window_query.single()).unwrap();
+        replace_simulator(
+            &mut simulator,
+            &mut camera,
+            &registry,
+            &context,
+            &primary_window.renderer,
+            &settings.rules.rule_set(),
+            canvas_size,
+            settings.seed,
+        );
+    }
+    settings.canvas_size = canvas_size;
+    simulator.set_matter(&matter);
+    simulator.set_steps(0);
+    history.clear();
+    info!("Imported {}", settings.image_path);
+}
+
+/// Step simulation as many times as the clock says. When paused, only recolor so that drawing
+/// shows.
+fn simulate(mut sim_pipeline: ResMut<CASimulator>, mut clock: ResMut<SimClock>, time: Res<Time>) {
+    let steps = clock.advance(time.delta_seconds());
+    if steps == 0 {
+        sim_pipeline.update_image();
+    } else {
+        sim_pipeline.step_many(steps);
+    }
+}
+
+/// Write a PNG of the canvas when requested
+fn take_screenshot(
+    mut events: EventReader<TakeScreenshot>,
+    simulator: Res<CASimulator>,
+    settings: Res<DynamicSettings>,
+    registry: Res<MatterRegistry>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    let path = format!("{}/step_{:08}.png", SCREENSHOT_DIR, simulator.steps());
+    match export_png(&path, simulator.canvas_size(), &simulator.cells(), |cell| {
+        settings.rules.cell_color(&registry, cell)
+    }) {
+        Ok(()) => info!("Saved screenshot to {}", path),
+        Err(e) => error!("Failed to save screenshot to {}: {}", path, e),
+    }
+}
+
+/// Record frames while recording is on. A new recording starts numbering frames from zero.
+fn record_frames(
+    mut settings: ResMut<DynamicSettings>,
+    simulator: Res<CASimulator>,
+    registry: Res<MatterRegistry>,
+    mut recorder: Local<Option<Recorder>>,
+) {
+    if !settings.recording {
+        *recorder = None;
+        return;
+    }
+    let recorder = recorder
+        .get_or_insert_with(|| Recorder::new(&settings.record_dir, settings.record_interval));
+    let rules = settings.rules;
+    if let Err(e) = recorder.record(&simulator, |cell| rules.cell_color(&registry, cell)) {
+        error!("Failed to record frame, stopping recording: {}", e);
+        settings.recording = false;
+    }
 }
 
 /// Render the simulation
@@ -163,6 +581,11 @@ fn render(
         Ok(f) => f,
     };
 
+    // Sample the canvas only once the compute work writing it has finished
+    let before = match simulator.take_future() {
+        Some(compute) => before.join(compute).boxed(),
+        None => before,
+    };
     let canvas_image = simulator.color_image();
 
     // Render
@@ -188,28 +611,19 @@ fn update_camera(window_query: Query<&Window>, mut camera: ResMut<OrthographicCa
     camera.update(window.width(), window.height());
 }
 
-/// Input actions for camera movement, zoom and pausing
+/// Input actions for camera movement, zoom, matter selection and pausing
+#[allow(clippy::too_many_arguments)]
 fn input_actions(
     time: Res<Time>,
     mut camera: ResMut<OrthographicCamera>,
+    mut settings: ResMut<DynamicSettings>,
+    mut clock: ResMut<SimClock>,
+    registry: Res<MatterRegistry>,
     keyboard_input: Res<Input<KeyCode>>,
     mut mouse_input_events: EventReader<MouseWheel>,
+    window_query: Query<Entity, With<PrimaryWindow>>,
+    windows: NonSend<BevyVulkanoWindows>,
 ) {
-    // Move camera with arrows & WASD
-    let up = keyboard_input.pressed(KeyCode::W) || keyboard_input.pressed(KeyCode::Up);
-    let down = keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down);
-    let left = keyboard_input.pressed(KeyCode::A) || keyboard_input.pressed(KeyCode::Left);
-    let right = keyboard_input.pressed(KeyCode::D) || keyboard_input.pressed(KeyCode::Right);
-
-    let x_axis = -(right as i8) + left as i8;
-    let y_axis = -(up as i8) + down as i8;
-
-    let mut move_delta = Vec2::new(x_axis as f32, y_axis as f32);
-    if move_delta != Vec2::ZERO {
-        move_delta /= move_delta.length();
-        camera.pos += move_delta * time.delta_seconds() * CAMERA_MOVE_SPEED;
-    }
-
     // Zoom camera with mouse scroll
     for e in mouse_input_events.iter() {
         if e.y < 0.0 {
@@ -218,20 +632,193 @@ fn input_actions(
             camera.scale *= 1.0 / 1.05;
         }
     }
+
+    if gui_wants_keyboard(&window_query, &windows) {
+        return;
+    }
+
+    // Move camera with arrows & WASD, unless used in a Ctrl shortcut
+    if !ctrl_pressed(&keyboard_input) {
+        let up = keyboard_input.pressed(KeyCode::W) || keyboard_input.pressed(KeyCode::Up);
+        let down = keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down);
+        let left = keyboard_input.pressed(KeyCode::A) || keyboard_input.pressed(KeyCode::Left);
+        let right = keyboard_input.pressed(KeyCode::D) || keyboard_input.pressed(KeyCode::Right);
+
+        let x_axis = -(right as i8) + left as i8;
+        let y_axis = -(up as i8) + down as i8;
+
+        let mut move_delta = Vec2::new(x_axis as f32, y_axis as f32);
+        if move_delta != Vec2::ZERO {
+            move_delta /= move_delta.length();
+            camera.pos += move_delta * time.delta_seconds() * CAMERA_MOVE_SPEED;
+        }
+    }
+
+    // Pause with space, single step with period
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        clock.toggle_pause();
+    }
+    if keyboard_input.just_pressed(KeyCode::Period) {
+        clock.step_once();
+    }
+
+    // Select matter with number keys
+    for (id, key) in MATTER_SHORTCUTS.iter().enumerate() {
+        let id = MatterId(id as u32);
+        if keyboard_input.just_pressed(*key) && registry.get(id).is_some() {
+            settings.draw_matter = id;
+        }
+    }
+}
+
+fn ctrl_pressed(keyboard_input: &Input<KeyCode>) -> bool {
+    keyboard_input.any_pressed([KeyCode::LControl, KeyCode::RControl])
+}
+
+/// Whether keys go to the gui, e.g. while typing in a text field, and must not act on the canvas
+fn gui_wants_keyboard(
+    window_query: &Query<Entity, With<PrimaryWindow>>,
+    windows: &BevyVulkanoWindows,
+) -> bool {
+    let primary_window = windows.get_vulkano_window(window_query.single()).unwrap();
+    primary_window.gui.context().wants_keyboard_input()
 }
 
-/// Draw matter to our grid
+/// Ctrl+S saves and Ctrl+O loads the canvas, Ctrl+I imports an image, Ctrl+Z undoes and Ctrl+Y
+/// (or Ctrl+Shift+Z) redoes strokes. F12 takes a screenshot.
+#[allow(clippy::too_many_arguments)]
+fn shortcuts(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut save_canvas: EventWriter<SaveCanvas>,
+    mut load_canvas: EventWriter<LoadCanvas>,
+    mut import_image: EventWriter<ImportImage>,
+    mut take_screenshot: EventWriter<TakeScreenshot>,
+    mut undo_stroke: EventWriter<UndoStroke>,
+    mut redo_stroke: EventWriter<RedoStroke>,
+    window_query: Query<Entity, With<PrimaryWindow>>,
+    windows: NonSend<BevyVulkanoWindows>,
+) {
+    if gui_wants_keyboard(&window_query, &windows) {
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::F12) {
+        take_screenshot.send(TakeScreenshot);
+    }
+    if !ctrl_pressed(&keyboard_input) {
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::S) {
+        save_canvas.send(SaveCanvas);
+    }
+    if keyboard_input.just_pressed(KeyCode::O) {
+        load_canvas.send(LoadCanvas);
+    }
+    if keyboard_input.just_pressed(KeyCode::I) {
+        import_image.send(ImportImage);
+    }
+    let shift = keyboard_input.any_pressed([KeyCode::LShift, KeyCode::RShift]);
+    if keyboard_input.just_pressed(KeyCode::Y) || (shift && keyboard_input.just_pressed(KeyCode::Z))
+    {
+        redo_stroke.send(RedoStroke);
+    } else if keyboard_input.just_pressed(KeyCode::Z) {
+        undo_stroke.send(UndoStroke);
+    }
+}
+
+/// Draw matter to our grid with the brush tool. Drawing from press to release is one stroke in
+/// undo history.
+#[allow(clippy::too_many_arguments)]
 fn draw_matter(
     mut simulator: ResMut<CASimulator>,
+    mut history: ResMut<History>,
     prev: Res<PreviousMousePos>,
     current: Res<CurrentMousePos>,
+    mut drag_start: ResMut<DragStart>,
     mouse_button_input: Res<Input<MouseButton>>,
     settings: Res<DynamicSettings>,
+    window_query: Query<Entity, With<PrimaryWindow>>,
+    windows: NonSend<BevyVulkanoWindows>,
+    mut spray_seed: Local<u32>,
+) {
+    let canvas_size = simulator.canvas_size();
+    let pressed = mouse_button_input.pressed(MouseButton::Left);
+    let just_pressed = mouse_button_input.just_pressed(MouseButton::Left);
+    // Don't start drawing under the gui, e.g. when picking matter
+    let primary_window = windows.get_vulkano_window(window_query.single()).unwrap();
+    let over_gui = primary_window.gui.context().wants_pointer_input();
+    let matter = settings.draw_matter;
+    let changes = match current.0 {
+        // Drags started on the canvas may end over the gui
+        Some(current) if !over_gui || drag_start.0.is_some() => match settings.brush_tool {
+            BrushTool::Round | BrushTool::Square | BrushTool::Spray if pressed => {
+                *spray_seed = spray_seed.wrapping_add(1);
+                let segment = get_canvas_segment(prev.0, current, canvas_size);
+                let stroke = brush_stroke(&settings, segment, *spray_seed);
+                simulator.draw_stroke(&stroke, matter, settings.replace_only)
+            }
+            BrushTool::Line | BrushTool::Rectangle => {
+                if just_pressed {
+                    drag_start.0 = Some(current);
+                }
+                match drag_start.0 {
+                    Some(start) if !pressed => {
+                        let segment = [
+                            start.canvas_cell(canvas_size),
+                            current.canvas_cell(canvas_size),
+                        ];
+                        let stroke = brush_stroke(&settings, segment, 0);
+                        simulator.draw_stroke(&stroke, matter, settings.replace_only)
+                    }
+                    _ => vec![],
+                }
+            }
+            BrushTool::FloodFill if just_pressed => {
+                let cells = flood_fill(
+                    &simulator.matter(),
+                    canvas_size,
+                    current.canvas_cell(canvas_size),
+                );
+                simulator.draw_matter(&cells, matter, settings.replace_only)
+            }
+            _ => vec![],
+        },
+        _ => vec![],
+    };
+    if !changes.is_empty() {
+        history.record(&changes);
+    }
+    if !pressed {
+        drag_start.0 = None;
+        history.end_stroke();
+    }
+}
+
+/// Stroke of the brush tool along a segment. Not for flood fill, which has no shape.
+fn brush_stroke(settings: &DynamicSettings, segment: [IVec2; 2], seed: u32) -> BrushStroke {
+    BrushStroke {
+        shape: settings.brush_tool.shape().unwrap(),
+        segments: vec![segment],
+        radius: settings.brush_radius,
+        density: settings.spray_density,
+        seed,
+    }
+}
+
+/// Undo & redo strokes when requested
+fn undo_redo(
+    mut undo_events: EventReader<UndoStroke>,
+    mut redo_events: EventReader<RedoStroke>,
+    mut simulator: ResMut<CASimulator>,
+    mut history: ResMut<History>,
 ) {
-    if let Some(current) = current.0 {
-        if mouse_button_input.pressed(MouseButton::Left) {
-            let line = get_canvas_line(prev.0, current);
-            simulator.draw_matter(&line, settings.brush_radius, settings.draw_matter);
+    for _ in undo_events.iter() {
+        if let Some(changes) = history.undo() {
+            simulator.apply_changes(&changes);
+        }
+    }
+    for _ in redo_events.iter() {
+        if let Some(changes) = history.redo() {
+            simulator.apply_changes(&changes);
         }
     }
 }
@@ -244,6 +831,10 @@ pub struct PreviousMousePos(pub Option<MousePos>);
 #[derive(Debug, Copy, Clone, Resource)]
 pub struct CurrentMousePos(pub Option<MousePos>);
 
+/// Mouse position where the line or rectangle being dragged started
+#[derive(Debug, Copy, Clone, Resource)]
+pub struct DragStart(pub Option<MousePos>);
+
 /// Update mouse position
 fn update_mouse(
     window_query: Query<&Window>,
@@ -0,0 +1,598 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use bevy::prelude::Resource;
+use serde::Deserialize;
+use strum_macros::Display;
+use vulkano::buffer::BufferContents;
+
+use crate::cell::AMBIENT_TEMPERATURE;
+
+/// Path of the matter definitions loaded at startup
+pub const MATTER_DEFINITIONS_PATH: &str = "assets/matter.ron";
+
+/// Lowest valid temperature in degrees Celsius
+const ABSOLUTE_ZERO: f32 = -273.15;
+
+/// Conductivity of matters that don't define one
+const DEFAULT_CONDUCTIVITY: f32 = 0.1;
+
+/// Matter identifier stored in the simulation grid. Ids index the matter registry and thus the
+/// matter properties buffer.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MatterId(pub u32);
+
+impl MatterId {
+    /// The empty matter is always the first matter of the registry
+    pub const EMPTY: MatterId = MatterId(0);
+}
+
+/// State of matter decides how matter moves. Must match the `STATE_*` defines in
+/// `includes.glsl`.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display, Deserialize)]
+pub enum MatterState {
+    Empty = 0,
+    Solid = 1,
+    Powder = 2,
+    Liquid = 3,
+    Gas = 4,
+}
+
+/// Matter properties as laid out in the shaders' matter properties buffer
+#[repr(C)]
+#[derive(Debug, Copy, Clone, BufferContents)]
+pub struct MatterProperties {
+    /// RGBA color
+    pub color: u32,
+    /// [`MatterState`] as u32
+    pub state: u32,
+    /// Denser matter sinks through lighter liquids & gases
+    pub density: f32,
+    /// Range of this matter's reactions in the reactions buffer
+    pub reactions_start: u32,
+    pub reactions_len: u32,
+    /// Temperature of new cells
+    pub temperature: f32,
+    /// Share (0.0 - 1.0) of the temperature difference to a neighbor evened out per step
+    pub conductivity: f32,
+    /// Cells hotter than `heated_above` become `heated_into`, infinity if the matter has no
+    /// such transition
+    pub heated_above: f32,
+    pub heated_into: u32,
+    /// Cells colder than `cooled_below` become `cooled_into`, minus infinity if the matter has
+    /// no such transition
+    pub cooled_below: f32,
+    pub cooled_into: u32,
+}
+
+/// Reaction as laid out in the shaders' reactions buffer: matter touching `reagent` becomes
+/// `product`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, BufferContents)]
+pub struct MatterReaction {
+    pub reagent: u32,
+    pub product: u32,
+}
+
+/// Reaction as written in the definitions file, referring to matters by name
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReactionDefinition {
+    pub with: String,
+    pub becomes: String,
+}
+
+/// Phase transition as written in the definitions file: matter heated above or cooled below a
+/// temperature becomes another matter, e.g. ice melting into water
+#[derive(Debug, Clone, Deserialize)]
+pub enum TransitionDefinition {
+    Above { temperature: f32, becomes: String },
+    Below { temperature: f32, becomes: String },
+}
+
+impl TransitionDefinition {
+    fn temperature(&self) -> f32 {
+        match self {
+            TransitionDefinition::Above {
+                temperature, ..
+            }
+            | TransitionDefinition::Below {
+                temperature, ..
+            } => *temperature,
+        }
+    }
+
+    fn becomes(&self) -> &String {
+        match self {
+            TransitionDefinition::Above {
+                becomes, ..
+            }
+            | TransitionDefinition::Below {
+                becomes, ..
+            } => becomes,
+        }
+    }
+}
+
+/// Matter as written in the definitions file
+#[derive(Debug, Clone, Deserialize)]
+pub struct MatterDefinition {
+    pub name: String,
+    /// RGBA color
+    pub color: u32,
+    pub state: MatterState,
+    pub density: f32,
+    #[serde(default)]
+    pub reactions: Vec<ReactionDefinition>,
+    /// Temperature of new cells in degrees Celsius
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    #[serde(default = "default_conductivity")]
+    pub conductivity: f32,
+    /// At most one transition above and one below a temperature
+    #[serde(default)]
+    pub transitions: Vec<TransitionDefinition>,
+}
+
+fn default_temperature() -> f32 {
+    AMBIENT_TEMPERATURE
+}
+
+fn default_conductivity() -> f32 {
+    DEFAULT_CONDUCTIVITY
+}
+
+#[derive(Debug, Deserialize)]
+struct MatterDefinitionsFile {
+    matters: Vec<MatterDefinition>,
+}
+
+/// Errors from loading and validating matter definitions
+#[derive(Debug)]
+pub enum MatterRegistryError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    NoMatters,
+    EmptyNotFirst,
+    DuplicateName(String),
+    InvalidState {
+        matter: String,
+    },
+    InvalidValue {
+        matter: String,
+        field: &'static str,
+        value: f32,
+    },
+    UnknownReactionMatter {
+        matter: String,
+        name: String,
+    },
+    UnknownTransitionMatter {
+        matter: String,
+        name: String,
+    },
+    TooManyTransitions {
+        matter: String,
+    },
+    OverlappingTransitions {
+        matter: String,
+    },
+}
+
+impl fmt::Display for MatterRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatterRegistryError::Io(e) => write!(f, "could not read matter definitions: {}", e),
+            MatterRegistryError::Parse(e) => write!(f, "invalid matter definitions: {}", e),
+            MatterRegistryError::NoMatters => {
+                write!(
+                    f,
+                    "at least one matter in addition to empty matter must be defined"
+                )
+            }
+            MatterRegistryError::EmptyNotFirst => {
+                write!(
+                    f,
+                    "the first matter must be the empty matter with state `Empty`"
+                )
+            }
+            MatterRegistryError::DuplicateName(name) => {
+                write!(f, "matter `{}` is defined more than once", name)
+            }
+            MatterRegistryError::InvalidState {
+                matter,
+            } => {
+                write!(
+                    f,
+                    "matter `{}` can't have state `Empty`, only the first matter can",
+                    matter
+                )
+            }
+            MatterRegistryError::InvalidValue {
+                matter,
+                field,
+                value,
+            } => write!(f, "matter `{}` has invalid {}: {}", matter, field, value),
+            MatterRegistryError::UnknownReactionMatter {
+                matter,
+                name,
+            } => write!(
+                f,
+                "reaction of matter `{}` refers to unknown matter `{}`",
+                matter, name
+            ),
+            MatterRegistryError::UnknownTransitionMatter {
+                matter,
+                name,
+            } => write!(
+                f,
+                "transition of matter `{}` refers to unknown matter `{}`",
+                matter, name
+            ),
+            MatterRegistryError::TooManyTransitions {
+                matter,
+            } => write!(
+                f,
+                "matter `{}` can have only one transition above and one below a temperature",
+                matter
+            ),
+            MatterRegistryError::OverlappingTransitions {
+                matter,
+            } => write!(
+                f,
+                "matter `{}` must transition above a higher temperature than below",
+                matter
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MatterRegistryError {}
+
+/// All matters of the simulation, indexed by [`MatterId`]
+#[derive(Debug, Clone, Resource)]
+pub struct MatterRegistry {
+    definitions: Vec<MatterDefinition>,
+    ids: HashMap<String, MatterId>,
+}
+
+impl MatterRegistry {
+    /// Load and validate matter definitions from a RON file
+    pub fn load(path: impl AsRef<Path>) -> Result<MatterRegistry, MatterRegistryError> {
+        let source = std::fs::read_to_string(path).map_err(MatterRegistryError::Io)?;
+        MatterRegistry::from_ron(&source)
+    }
+
+    /// Parse and validate matter definitions from RON source
+    pub fn from_ron(source: &str) -> Result<MatterRegistry, MatterRegistryError> {
+        let file: MatterDefinitionsFile =
+            ron::from_str(source).map_err(MatterRegistryError::Parse)?;
+        MatterRegistry::new(file.matters)
+    }
+
+    /// Validate matter definitions. The first matter must be the empty matter.
+    pub fn new(definitions: Vec<MatterDefinition>) -> Result<MatterRegistry, MatterRegistryError> {
+        match definitions.first() {
+            None => return Err(MatterRegistryError::NoMatters),
+            Some(empty) if empty.state != MatterState::Empty => {
+                return Err(MatterRegistryError::EmptyNotFirst)
+            }
+            _ => {}
+        }
+        if definitions.len() < 2 {
+            return Err(MatterRegistryError::NoMatters);
+        }
+        let mut ids = HashMap::new();
+        for (i, definition) in definitions.iter().enumerate() {
+            if ids
+                .insert(definition.name.clone(), MatterId(i as u32))
+                .is_some()
+            {
+                return Err(MatterRegistryError::DuplicateName(definition.name.clone()));
+            }
+            if i > 0 && definition.state == MatterState::Empty {
+                return Err(MatterRegistryError::InvalidState {
+                    matter: definition.name.clone(),
+                });
+            }
+            let invalid = |field, value: f32| MatterRegistryError::InvalidValue {
+                matter: definition.name.clone(),
+                field,
+                value,
+            };
+            if !definition.density.is_finite() || definition.density < 0.0 {
+                return Err(invalid("density", definition.density));
+            }
+            if !definition.temperature.is_finite() || definition.temperature < ABSOLUTE_ZERO {
+                return Err(invalid("temperature", definition.temperature));
+            }
+            if !(0.0..=1.0).contains(&definition.conductivity) {
+                return Err(invalid("conductivity", definition.conductivity));
+            }
+            for transition in definition.transitions.iter() {
+                if !transition.temperature().is_finite() {
+                    return Err(invalid("transition temperature", transition.temperature()));
+                }
+            }
+            let above = definition
+                .transitions
+                .iter()
+                .filter(|t| matches!(t, TransitionDefinition::Above { .. }))
+                .count();
+            if above > 1 || definition.transitions.len() - above > 1 {
+                return Err(MatterRegistryError::TooManyTransitions {
+                    matter: definition.name.clone(),
+                });
+            }
+            // Cells can't be both too hot and too cold
+            let (mut heated_above, mut cooled_below) = (f32::INFINITY, f32::NEG_INFINITY);
+            for transition in definition.transitions.iter() {
+                match transition {
+                    TransitionDefinition::Above {
+                        temperature, ..
+                    } => heated_above = *temperature,
+                    TransitionDefinition::Below {
+                        temperature, ..
+                    } => cooled_below = *temperature,
+                }
+            }
+            if heated_above <= cooled_below {
+                return Err(MatterRegistryError::OverlappingTransitions {
+                    matter: definition.name.clone(),
+                });
+            }
+        }
+        for definition in definitions.iter() {
+            for reaction in definition.reactions.iter() {
+                for name in [&reaction.with, &reaction.becomes] {
+                    if !ids.contains_key(name) {
+                        return Err(MatterRegistryError::UnknownReactionMatter {
+                            matter: definition.name.clone(),
+                            name: name.clone(),
+                        });
+                    }
+                }
+            }
+            for transition in definition.transitions.iter() {
+                if !ids.contains_key(transition.becomes()) {
+                    return Err(MatterRegistryError::UnknownTransitionMatter {
+                        matter: definition.name.clone(),
+                        name: transition.becomes().clone(),
+                    });
+                }
+            }
+        }
+        Ok(MatterRegistry {
+            definitions,
+            ids,
+        })
+    }
+
+    /// Matter definition by id
+    pub fn get(&self, id: MatterId) -> Option<&MatterDefinition> {
+        self.definitions.get(id.0 as usize)
+    }
+
+    /// Id of the matter with the given name
+    pub fn id(&self, name: &str) -> Option<MatterId> {
+        self.ids.get(name).copied()
+    }
+
+    /// All matters with their ids, starting from the empty matter
+    pub fn matters(&self) -> impl Iterator<Item = (MatterId, &MatterDefinition)> {
+        self.definitions
+            .iter()
+            .enumerate()
+            .map(|(i, definition)| (MatterId(i as u32), definition))
+    }
+
+    /// Version of the matter ids, changing whenever matters are renamed, added, removed or
+    /// reordered. Saved canvases are only valid with the same version.
+    pub fn version(&self) -> u64 {
+        // FNV-1a, stable across builds unlike std's hasher
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for definition in self.definitions.iter() {
+            for byte in definition.name.bytes().chain([0]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+
+    /// Matter properties indexed by matter id, to be uploaded for the shaders
+    pub fn properties(&self) -> Vec<MatterProperties> {
+        let mut reactions_start = 0;
+        self.definitions
+            .iter()
+            .enumerate()
+            .map(|(i, definition)| {
+                let mut properties = MatterProperties {
+                    color: definition.color,
+                    state: definition.state as u32,
+                    density: definition.density,
+                    reactions_start,
+                    reactions_len: definition.reactions.len() as u32,
+                    temperature: definition.temperature,
+                    conductivity: definition.conductivity,
+                    heated_above: f32::INFINITY,
+                    heated_into: i as u32,
+                    cooled_below: f32::NEG_INFINITY,
+                    cooled_into: i as u32,
+                };
+                for transition in definition.transitions.iter() {
+                    match transition {
+                        TransitionDefinition::Above {
+                            temperature,
+                            becomes,
+                        } => {
+                            properties.heated_above = *temperature;
+                            properties.heated_into = self.ids[becomes].0;
+                        }
+                        TransitionDefinition::Below {
+                            temperature,
+                            becomes,
+                        } => {
+                            properties.cooled_below = *temperature;
+                            properties.cooled_into = self.ids[becomes].0;
+                        }
+                    }
+                }
+                reactions_start += properties.reactions_len;
+                properties
+            })
+            .collect()
+    }
+
+    /// All reactions in the order given by [`MatterProperties::reactions_start`]
+    pub fn reactions(&self) -> Vec<MatterReaction> {
+        self.definitions
+            .iter()
+            .flat_map(|definition| definition.reactions.iter())
+            .map(|reaction| MatterReaction {
+                reagent: self.ids[&reaction.with].0,
+                product: self.ids[&reaction.becomes].0,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry_with_water(water: &str) -> Result<MatterRegistry, MatterRegistryError> {
+        MatterRegistry::from_ron(&format!(
+            r#"(matters: [
+                (name: "empty", color: 0, state: Empty, density: 0.0),
+                (name: "ice", color: 0, state: Solid, density: 0.9),
+                (name: "water", color: 0, state: Liquid, density: 1.0, {}),
+            ])"#,
+            water
+        ))
+    }
+
+    fn registry(matters: &[&str]) -> Result<MatterRegistry, MatterRegistryError> {
+        MatterRegistry::from_ron(&format!("(matters: [{}])", matters.join(", ")))
+    }
+
+    const EMPTY: &str = r#"(name: "empty", color: 0, state: Empty, density: 0.0)"#;
+    const SAND: &str = r#"(name: "sand", color: 0, state: Powder, density: 1.6)"#;
+
+    #[test]
+    fn loads_default_definitions() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        assert_eq!(
+            registry.get(MatterId::EMPTY).unwrap().state,
+            MatterState::Empty
+        );
+        assert_eq!(registry.properties().len(), registry.matters().count());
+        for (id, definition) in registry.matters() {
+            assert_eq!(registry.id(&definition.name), Some(id));
+        }
+        assert_eq!(registry.id("plasma"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_definitions() {
+        assert!(matches!(
+            registry(&["(matters"]),
+            Err(MatterRegistryError::Parse(_))
+        ));
+        assert!(matches!(
+            registry(&[EMPTY]),
+            Err(MatterRegistryError::NoMatters)
+        ));
+        assert!(matches!(
+            registry(&[SAND, EMPTY]),
+            Err(MatterRegistryError::EmptyNotFirst)
+        ));
+        assert!(matches!(
+            registry(&[EMPTY, SAND, SAND]),
+            Err(MatterRegistryError::DuplicateName(name)) if name == "sand"
+        ));
+        assert!(matches!(
+            registry(&[
+                EMPTY,
+                r#"(name: "void", color: 0, state: Empty, density: 0.0)"#
+            ]),
+            Err(MatterRegistryError::InvalidState { .. })
+        ));
+        assert!(matches!(
+            registry(&[
+                EMPTY,
+                r#"(name: "sand", color: 0, state: Powder, density: -1.0)"#
+            ]),
+            Err(MatterRegistryError::InvalidValue {
+                field: "density",
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry(&[
+                EMPTY,
+                r#"(
+                    name: "sand", color: 0, state: Powder, density: 1.6,
+                    reactions: [(with: "acid", becomes: "empty")],
+                )"#
+            ]),
+            Err(MatterRegistryError::UnknownReactionMatter { name, .. }) if name == "acid"
+        ));
+    }
+
+    #[test]
+    fn transitions_become_properties() {
+        let registry = registry_with_water(
+            r#"conductivity: 0.5, transitions: [Below(temperature: 0.0, becomes: "ice")]"#,
+        )
+        .unwrap();
+        let properties = registry.properties();
+        let (ice, water) = (properties[1], properties[2]);
+        assert_eq!(ice.temperature, AMBIENT_TEMPERATURE);
+        assert_eq!(ice.conductivity, DEFAULT_CONDUCTIVITY);
+        assert_eq!(ice.heated_above, f32::INFINITY);
+        assert_eq!(ice.cooled_below, f32::NEG_INFINITY);
+        assert_eq!(water.conductivity, 0.5);
+        assert_eq!((water.cooled_below, water.cooled_into), (0.0, 1));
+        assert_eq!(water.heated_into, 2);
+    }
+
+    #[test]
+    fn rejects_invalid_thermal_values() {
+        assert!(matches!(
+            registry_with_water("temperature: -300.0"),
+            Err(MatterRegistryError::InvalidValue {
+                field: "temperature",
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry_with_water("conductivity: 1.5"),
+            Err(MatterRegistryError::InvalidValue {
+                field: "conductivity",
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry_with_water(r#"transitions: [Above(temperature: 100.0, becomes: "steam")]"#),
+            Err(MatterRegistryError::UnknownTransitionMatter { .. })
+        ));
+        assert!(matches!(
+            registry_with_water(
+                r#"transitions: [
+                    Below(temperature: 0.0, becomes: "ice"),
+                    Below(temperature: -10.0, becomes: "ice"),
+                ]"#
+            ),
+            Err(MatterRegistryError::TooManyTransitions { .. })
+        ));
+        assert!(matches!(
+            registry_with_water(
+                r#"transitions: [
+                    Above(temperature: 0.0, becomes: "ice"),
+                    Below(temperature: 0.0, becomes: "ice"),
+                ]"#
+            ),
+            Err(MatterRegistryError::OverlappingTransitions { .. })
+        ));
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use bevy::math::UVec2;
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+use serde::Deserialize;
+
+use crate::{
+    matter::{MatterId, MatterRegistry},
+    MAX_CANVAS_SIZE,
+};
+
+/// Path of the optional table mapping exact pixel colors to matters
+pub const COLOR_MAPPING_PATH: &str = "assets/png_colors.ron";
+/// Path used by the image import unless changed in the gui
+pub const DEFAULT_IMAGE_PATH: &str = "images/scene.png";
+/// Directory screenshots are written to
+pub const SCREENSHOT_DIR: &str = "screenshots";
+
+/// Errors from reading & writing images and color mappings
+#[derive(Debug)]
+pub enum ImageError {
+    Io(std::io::Error),
+    Decode(png::DecodingError),
+    Encode(png::EncodingError),
+    MappingParse(ron::error::SpannedError),
+    UnknownMatter(String),
+    InvalidSize(UVec2),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "{}", e),
+            ImageError::Decode(e) => write!(f, "invalid png: {}", e),
+            ImageError::Encode(e) => write!(f, "could not write png: {}", e),
+            ImageError::MappingParse(e) => write!(f, "invalid color mapping: {}", e),
+            ImageError::UnknownMatter(name) => {
+                write!(f, "color mapping refers to unknown matter `{}`", name)
+            }
+            ImageError::InvalidSize(size) => write!(
+                f,
+                "image size {}x{} exceeds maximum canvas size {}",
+                size.x, size.y, MAX_CANVAS_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<std::io::Error> for ImageError {
+    fn from(e: std::io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ColorMappingFile {
+    /// RGBA color to matter name
+    colors: HashMap<u32, String>,
+}
+
+/// Maps pixel colors to matters. Colors in the mapping table map to their matter, transparent
+/// pixels to the empty matter and others to the matter with the nearest color in the palette.
+#[derive(Debug, Clone)]
+pub struct ColorMapping {
+    table: HashMap<u32, MatterId>,
+    palette: Vec<(MatterId, u32)>,
+}
+
+impl ColorMapping {
+    /// Mapping by nearest matter color only
+    pub fn new(registry: &MatterRegistry) -> ColorMapping {
+        ColorMapping {
+            table: HashMap::new(),
+            palette: registry
+                .matters()
+                .map(|(id, definition)| (id, definition.color))
+                .collect(),
+        }
+    }
+
+    /// Mapping with a table from a RON file, e.g. `(colors: {0x000000ff: "rock"})`. A missing file
+    /// means no table.
+    pub fn load(
+        path: impl AsRef<Path>,
+        registry: &MatterRegistry,
+    ) -> Result<ColorMapping, ImageError> {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(ColorMapping::new(registry))
+            }
+            Err(e) => return Err(ImageError::Io(e)),
+        };
+        ColorMapping::from_ron(&source, registry)
+    }
+
+    pub fn from_ron(source: &str, registry: &MatterRegistry) -> Result<ColorMapping, ImageError> {
+        let file: ColorMappingFile = ron::from_str(source).map_err(ImageError::MappingParse)?;
+        let mut mapping = ColorMapping::new(registry);
+        for (color, name) in file.colors {
+            let id = registry.id(&name).ok_or(ImageError::UnknownMatter(name))?;
+            mapping.table.insert(color, id);
+        }
+        Ok(mapping)
+    }
+
+    /// Matter for an RGBA color
+    pub fn matter(&self, color: u32) -> MatterId {
+        if let Some(&id) = self.table.get(&color) {
+            return id;
+        }
+        if color & 0xff == 0 {
+            return MatterId::EMPTY;
+        }
+        self.palette
+            .iter()
+            .min_by_key(|(_, palette_color)| color_distance(color, *palette_color))
+            .map_or(MatterId::EMPTY, |(id, _)| *id)
+    }
+}
+
+/// Squared distance of RGBA colors
+fn color_distance(a: u32, b: u32) -> u32 {
+    a.to_be_bytes()
+        .iter()
+        .zip(b.to_be_bytes().iter())
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// Read a PNG into a matter grid of the image's size. The first image row becomes the top of the
+/// canvas.
+pub fn import_png(
+    path: impl AsRef<Path>,
+    mapping: &ColorMapping,
+) -> Result<(UVec2, Vec<u32>), ImageError> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(ImageError::Decode)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(ImageError::Decode)?;
+    let size = UVec2::new(info.width, info.height);
+    if size.x > MAX_CANVAS_SIZE || size.y > MAX_CANVAS_SIZE {
+        return Err(ImageError::InvalidSize(size));
+    }
+    let pixels = &buffer[..info.buffer_size()];
+    let rows = pixels.chunks_exact(info.line_size).rev();
+    let matter = rows
+        .flat_map(|row| {
+            row.chunks_exact(info.color_type.samples())
+                .map(|pixel| mapping.matter(rgba(info.color_type, pixel)).0)
+        })
+        .collect();
+    Ok((size, matter))
+}
+
+/// Write a grid as PNG colored by `cell_color`, e.g. matter colors, creating its directory if
+/// needed. The top of the canvas becomes the first image row.
+pub fn export_png<T>(
+    path: impl AsRef<Path>,
+    canvas_size: UVec2,
+    cells: &[T],
+    cell_color: impl Fn(&T) -> u32,
+) -> Result<(), ImageError> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let pixels: Vec<u8> = cells
+        .chunks_exact(canvas_size.x as usize)
+        .rev()
+        .flatten()
+        .flat_map(|cell| cell_color(cell).to_be_bytes())
+        .collect();
+    let mut encoder = Encoder::new(
+        BufWriter::new(File::create(path)?),
+        canvas_size.x,
+        canvas_size.y,
+    );
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(ImageError::Encode)?;
+    writer
+        .write_image_data(&pixels)
+        .map_err(ImageError::Encode)?;
+    writer.finish().map_err(ImageError::Encode)
+}
+
+/// RGBA color of an 8 bit pixel
+fn rgba(color_type: ColorType, pixel: &[u8]) -> u32 {
+    let [r, g, b, a] = match (color_type, pixel) {
+        (ColorType::Grayscale, &[l]) => [l, l, l, 255],
+        (ColorType::GrayscaleAlpha, &[l, a]) => [l, l, l, a],
+        (ColorType::Rgb, &[r, g, b]) => [r, g, b, 255],
+        (ColorType::Rgba, &[r, g, b, a]) => [r, g, b, a],
+        // Indexed images are expanded to rgb(a) by the decoder
+        _ => unreachable!("unexpected {:?} pixel", color_type),
+    };
+    u32::from_be_bytes([r, g, b, a])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matter::MATTER_DEFINITIONS_PATH;
+
+    #[test]
+    fn nearest_color_maps_to_matter() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let mapping = ColorMapping::new(&registry);
+        // Slightly off sand color
+        assert_eq!(mapping.matter(0xc0b082ff), registry.id("sand").unwrap());
+        assert_eq!(mapping.matter(0xffffff00), MatterId::EMPTY);
+    }
+
+    #[test]
+    fn table_overrides_nearest_color() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let mapping =
+            ColorMapping::from_ron(r#"(colors: {0xc2b280ff: "rock"})"#, &registry).unwrap();
+        assert_eq!(mapping.matter(0xc2b280ff), registry.id("rock").unwrap());
+        assert!(matches!(
+            ColorMapping::from_ron(r#"(colors: {0x000000ff: "plasma"})"#, &registry),
+            Err(ImageError::UnknownMatter(_))
+        ));
+    }
+
+    #[test]
+    fn default_mapping_table_is_valid() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        ColorMapping::load(COLOR_MAPPING_PATH, &registry).unwrap();
+    }
+
+    #[test]
+    fn imports_png_bottom_up() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let path = std::env::temp_dir().join("cellular_automata_import_test.png");
+        let mut encoder = png::Encoder::new(File::create(&path).unwrap(), 2, 2);
+        encoder.set_color(ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        // Top row sand, bottom row water & rock
+        let [r, g, b, _] = 0xc2b280ffu32.to_be_bytes();
+        writer
+            .write_image_data(&[r, g, b, r, g, b, 0x1c, 0xa3, 0xec, 0x80, 0x80, 0x80])
+            .unwrap();
+        writer.finish().unwrap();
+
+        let (size, matter) = import_png(&path, &ColorMapping::new(&registry)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(size, UVec2::new(2, 2));
+        let [sand, water, rock] =
+            ["sand", "water", "rock"].map(|name| registry.id(name).unwrap().0);
+        assert_eq!(matter, vec![water, rock, sand, sand]);
+    }
+
+    #[test]
+    fn export_round_trips_through_import() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let path = std::env::temp_dir().join("cellular_automata_export_test.png");
+        let size = UVec2::new(3, 2);
+        let matter: Vec<u32> = registry.matters().map(|(id, _)| id.0).take(6).collect();
+        export_png(&path, size, &matter, |&cell| {
+            registry.get(MatterId(cell)).unwrap().color
+        })
+        .unwrap();
+        let imported = import_png(&path, &ColorMapping::new(&registry)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(imported, (size, matter));
+    }
+}
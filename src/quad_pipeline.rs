@@ -35,6 +35,9 @@ pub struct DrawQuadPipeline {
     pipeline: Arc<GraphicsPipeline>,
     subpass: Subpass,
     quad: Mesh,
+    sampler: Arc<Sampler>,
+    /// Descriptor set of the last drawn image, reused while the image stays the same
+    image_descriptor_set: Option<(Arc<dyn ImageViewAbstract>, Arc<PersistentDescriptorSet>)>,
     command_buffer_allocator: StandardCommandBufferAllocator,
     descriptor_set_allocator: StandardDescriptorSetAllocator,
 }
@@ -60,11 +63,21 @@ impl DrawQuadPipeline {
                 .build(gfx_queue.device().clone())
                 .unwrap()
         };
+        let sampler = Sampler::new(gfx_queue.device().clone(), SamplerCreateInfo {
+            mag_filter: Filter::Nearest,
+            min_filter: Filter::Nearest,
+            address_mode: [SamplerAddressMode::Repeat; 3],
+            mipmap_mode: SamplerMipmapMode::Nearest,
+            ..Default::default()
+        })
+        .unwrap();
         DrawQuadPipeline {
             gfx_queue,
             pipeline,
             subpass,
             quad,
+            sampler,
+            image_descriptor_set: None,
             command_buffer_allocator: StandardCommandBufferAllocator::new(
                 allocator.device().clone(),
                 Default::default(),
@@ -75,23 +88,24 @@ impl DrawQuadPipeline {
         }
     }
 
-    pub fn create_image_sampler_nearest_descriptor_set(
-        &self,
+    /// Descriptor set sampling `image` with the nearest sampler. Created only when the image
+    /// changes, e.g. when the canvas is recreated.
+    pub fn image_sampler_nearest_descriptor_set(
+        &mut self,
         image: Arc<dyn ImageViewAbstract>,
     ) -> Arc<PersistentDescriptorSet> {
+        if let Some((cached_image, set)) = &self.image_descriptor_set {
+            if Arc::ptr_eq(cached_image, &image) {
+                return set.clone();
+            }
+        }
         let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
-        let sampler = Sampler::new(self.gfx_queue.device().clone(), SamplerCreateInfo {
-            mag_filter: Filter::Nearest,
-            min_filter: Filter::Nearest,
-            address_mode: [SamplerAddressMode::Repeat; 3],
-            mipmap_mode: SamplerMipmapMode::Nearest,
-            ..Default::default()
-        })
-        .unwrap();
-        PersistentDescriptorSet::new(&self.descriptor_set_allocator, layout.clone(), [
-            WriteDescriptorSet::image_view_sampler(0, image.clone(), sampler),
+        let set = PersistentDescriptorSet::new(&self.descriptor_set_allocator, layout.clone(), [
+            WriteDescriptorSet::image_view_sampler(0, image.clone(), self.sampler.clone()),
         ])
-        .unwrap()
+        .unwrap();
+        self.image_descriptor_set = Some((image, set.clone()));
+        set
     }
 
     /// Draw input `image` on a quad at (0.0, 0.0), between -1.0 and 1.0
@@ -125,7 +139,7 @@ impl DrawQuadPipeline {
             ],
         };
 
-        let image_sampler_descriptor_set = self.create_image_sampler_nearest_descriptor_set(image);
+        let image_sampler_descriptor_set = self.image_sampler_nearest_descriptor_set(image);
         builder
             .set_viewport(0, [Viewport {
                 origin: [0.0, 0.0],
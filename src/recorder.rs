@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use crate::{
+    ca_simulator::CASimulator,
+    cell::Cell,
+    png_image::{export_png, ImageError},
+};
+
+/// Directory frames are recorded to unless changed in the gui
+pub const DEFAULT_RECORD_DIR: &str = "recordings";
+/// Steps between recorded frames unless changed in the gui
+pub const DEFAULT_RECORD_INTERVAL: u64 = 10;
+
+/// Records the canvas as numbered PNG frames every `interval` steps, e.g. to be turned into a
+/// video with `ffmpeg -i recordings/frame_%06d.png`.
+pub struct Recorder {
+    dir: PathBuf,
+    interval: u64,
+    frame: u64,
+    /// Steps divided by interval at the last frame
+    last_period: Option<u64>,
+}
+
+impl Recorder {
+    pub fn new(dir: impl Into<PathBuf>, interval: u64) -> Recorder {
+        Recorder {
+            dir: dir.into(),
+            interval: interval.max(1),
+            frame: 0,
+            last_period: None,
+        }
+    }
+
+    /// Write a frame colored by `cell_color` when starting and whenever the simulator has passed
+    /// another `interval` steps, also if several steps run in between calls. Returns the path of
+    /// the written frame.
+    pub fn record(
+        &mut self,
+        simulator: &CASimulator,
+        cell_color: impl Fn(&Cell) -> u32,
+    ) -> Result<Option<PathBuf>, ImageError> {
+        let period = simulator.steps() / self.interval;
+        if self.last_period == Some(period) {
+            return Ok(None);
+        }
+        let path = self.dir.join(format!("frame_{:06}.png", self.frame));
+        export_png(
+            &path,
+            simulator.canvas_size(),
+            &simulator.cells(),
+            cell_color,
+        )?;
+        self.frame += 1;
+        self.last_period = Some(period);
+        Ok(Some(path))
+    }
+}
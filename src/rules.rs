@@ -0,0 +1,330 @@
+use std::sync::Arc;
+
+use bevy::math::UVec2;
+use bytemuck::Pod;
+use vulkano::{
+    buffer::BufferContents,
+    descriptor_set::layout::DescriptorSetLayoutBinding,
+    device::{Device, Queue},
+    pipeline::ComputePipeline,
+    shader::{ShaderCreationError, ShaderModule, SpecializationConstants, SpecializationMapEntry},
+};
+
+use crate::{
+    cell::Cell,
+    lenia::{colormap, LeniaRule},
+    life::LifeRule,
+    matter::{MatterId, MatterRegistry},
+    utils::{create_compute_pipeline, storage_buffer_desc, storage_image_desc},
+    LOCAL_SIZE_X, LOCAL_SIZE_Y,
+};
+
+/// Most specialization constants of a kernel, including those of includes.glsl
+const MAX_KERNEL_CONSTANTS: usize = 16;
+
+/// Specialization constants of a kernel, 32 bits each, by constant id. Ids 0-4 are those of
+/// includes.glsl, the same for every kernel including it, and kernels' own constants follow.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KernelConstants([u32; MAX_KERNEL_CONSTANTS]);
+
+impl KernelConstants {
+    /// Constants of includes.glsl for a canvas size
+    pub fn new(canvas_size: UVec2) -> KernelConstants {
+        KernelConstants([0; MAX_KERNEL_CONSTANTS])
+            .with(0, canvas_size.x as i32)
+            .with(1, canvas_size.y as i32)
+            .with(2, MatterId::EMPTY.0)
+            .with(3, LOCAL_SIZE_X)
+            .with(4, LOCAL_SIZE_Y)
+    }
+
+    /// Set a constant of 32 bits, e.g. an `int`, `uint` or `float` constant of a kernel
+    pub fn with<T: Pod>(mut self, constant_id: u32, value: T) -> KernelConstants {
+        self.0[constant_id as usize] = bytemuck::cast(value);
+        self
+    }
+}
+
+// Safety: every entry is a 32 bit value of the array, which matches int, uint and float
+// constants. Entries of ids a kernel doesn't declare are ignored.
+unsafe impl SpecializationConstants for KernelConstants {
+    fn descriptors() -> &'static [SpecializationMapEntry] {
+        static DESCRIPTORS: [SpecializationMapEntry; MAX_KERNEL_CONSTANTS] = {
+            let mut descriptors = [SpecializationMapEntry {
+                constant_id: 0,
+                offset: 0,
+                size: 4,
+            }; MAX_KERNEL_CONSTANTS];
+            let mut id = 0;
+            while id < MAX_KERNEL_CONSTANTS {
+                descriptors[id].constant_id = id as u32;
+                descriptors[id].offset = 4 * id as u32;
+                id += 1;
+            }
+            descriptors
+        };
+        &DESCRIPTORS
+    }
+}
+
+/// Push constants of includes.glsl, the same for all kernels of a step
+#[repr(C)]
+#[derive(Debug, Copy, Clone, BufferContents)]
+pub struct StepConstants {
+    pub seed: u32,
+    pub steps: u32,
+}
+
+/// Descriptor layout of includes.glsl. The simulator binds one descriptor set of this layout to
+/// every rule kernel, so this must match the shader & the set's inputs.
+pub fn rule_descriptor_layout() -> Vec<(u32, DescriptorSetLayoutBinding)> {
+    vec![
+        (0, storage_buffer_desc()),
+        (1, storage_buffer_desc()),
+        (2, storage_image_desc()),
+        (3, storage_buffer_desc()),
+        (4, storage_buffer_desc()),
+    ]
+}
+
+/// Binding of a kernel's own storage buffer, following those of includes.glsl
+pub const RULE_BUFFER_BINDING: u32 = 5;
+
+/// A compute kernel run over the whole canvas. Kernels include includes.glsl: they read the
+/// current matter and write the next, and bind the simulator's descriptor set of their
+/// [`CellularRule::descriptor_layout`]. Kernels with push constants get the seed & step.
+pub trait CellularRule: Send + Sync {
+    /// Name, e.g. for logs
+    fn name(&self) -> &str;
+
+    /// Load the kernel's shader module
+    fn shader(&self, device: Arc<Device>) -> Arc<ShaderModule>;
+
+    /// Whether the kernel writes the next matter, after which the matter buffers are swapped so
+    /// that the next kernel sees it. Kernels only reading matter, e.g. coloring, don't swap.
+    fn swaps_matter(&self) -> bool {
+        true
+    }
+
+    /// Descriptor layout the kernel binds
+    fn descriptor_layout(&self) -> Vec<(u32, DescriptorSetLayoutBinding)> {
+        rule_descriptor_layout()
+    }
+
+    /// Data of the kernel's own read only storage buffer at [`RULE_BUFFER_BINDING`], e.g.
+    /// precomputed weights. Kernels with one add it to their descriptor layout.
+    fn rule_buffer(&self) -> Option<Vec<f32>> {
+        None
+    }
+
+    /// Specialization constants of the kernel for a canvas size. Kernels with constants of their
+    /// own add them to those of includes.glsl.
+    fn specialization_constants(&self, canvas_size: UVec2) -> KernelConstants {
+        KernelConstants::new(canvas_size)
+    }
+
+    /// Create the kernel's pipeline
+    fn create_pipeline(
+        &self,
+        compute_queue: Arc<Queue>,
+        canvas_size: UVec2,
+    ) -> Arc<ComputePipeline> {
+        let shader = self.shader(compute_queue.device().clone());
+        create_compute_pipeline(
+            compute_queue,
+            shader.entry_point("main").unwrap(),
+            self.descriptor_layout(),
+            &self.specialization_constants(canvas_size),
+        )
+    }
+}
+
+/// Rules of a simulation: rules run in dispatch order each step, after which `color` colors the
+/// canvas image from the matter
+pub struct RuleSet {
+    pub step: Vec<Box<dyn CellularRule>>,
+    pub color: Box<dyn CellularRule>,
+}
+
+impl RuleSet {
+    /// Matter reacting, exchanging heat, falling, rising, sliding and spreading, colored by matter
+    /// color
+    pub fn falling_sand() -> RuleSet {
+        RuleSet {
+            step: vec![
+                Box::new(Kernel::new("react", react_cs::load)),
+                Box::new(Kernel::new("heat", heat_cs::load)),
+                Box::new(Kernel::new("fall", fall_empty_cs::load)),
+                Box::new(Kernel::new("rise", rise_empty_cs::load)),
+                Box::new(Kernel::new("slide", slide_down_empty_cs::load)),
+                Box::new(Kernel::new("spread", horizontal_empty_cs::load)),
+            ],
+            color: Box::new(Kernel::color("matter color", color_cs::load)),
+        }
+    }
+
+    /// Life-like automaton, colored by matter color
+    pub fn life(rule: LifeRule) -> RuleSet {
+        RuleSet {
+            step: vec![Box::new(rule)],
+            color: Box::new(Kernel::color("matter color", color_cs::load)),
+        }
+    }
+
+    /// Lenia-style continuous automaton, colored by a colormap of the cells' states
+    pub fn lenia(rule: LeniaRule) -> RuleSet {
+        RuleSet {
+            step: vec![Box::new(rule)],
+            color: Box::new(Kernel::color("lenia colormap", lenia_color_cs::load)),
+        }
+    }
+}
+
+/// Rules to simulate, chosen in the gui or on the command line
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum RuleMode {
+    #[default]
+    FallingSand,
+    Life(LifeRule),
+    Lenia(LeniaRule),
+}
+
+impl RuleMode {
+    pub fn rule_set(&self) -> RuleSet {
+        match self {
+            RuleMode::FallingSand => RuleSet::falling_sand(),
+            RuleMode::Life(rule) => RuleSet::life(*rule),
+            RuleMode::Lenia(rule) => RuleSet::lenia(*rule),
+        }
+    }
+
+    /// RGBA color of a cell as the color kernel colors it, e.g. for exporting images
+    pub fn cell_color(&self, registry: &MatterRegistry, cell: &Cell) -> u32 {
+        match self {
+            RuleMode::FallingSand | RuleMode::Life(_) => registry
+                .get(MatterId(cell.matter))
+                .map_or(0, |definition| definition.color),
+            RuleMode::Lenia(_) => colormap(cell.state),
+        }
+    }
+}
+
+/// Loads a shader module, as generated by `vulkano_shaders::shader!`
+pub type ShaderLoader = fn(Arc<Device>) -> Result<Arc<ShaderModule>, ShaderCreationError>;
+
+/// Rule of a kernel using includes.glsl as is
+pub struct Kernel {
+    name: &'static str,
+    load: ShaderLoader,
+    swaps_matter: bool,
+}
+
+impl Kernel {
+    /// Kernel writing the next matter
+    pub fn new(name: &'static str, load: ShaderLoader) -> Kernel {
+        Kernel {
+            name,
+            load,
+            swaps_matter: true,
+        }
+    }
+
+    /// Kernel coloring the canvas image without writing matter
+    pub fn color(name: &'static str, load: ShaderLoader) -> Kernel {
+        Kernel {
+            name,
+            load,
+            swaps_matter: false,
+        }
+    }
+}
+
+impl CellularRule for Kernel {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn shader(&self, device: Arc<Device>) -> Arc<ShaderModule> {
+        (self.load)(device).unwrap()
+    }
+
+    fn swaps_matter(&self) -> bool {
+        self.swaps_matter
+    }
+}
+
+mod react_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/react.glsl"
+    }
+}
+
+mod heat_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/heat.glsl"
+    }
+}
+
+mod fall_empty_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/fall_empty.glsl"
+    }
+}
+
+mod rise_empty_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/rise_empty.glsl"
+    }
+}
+
+mod slide_down_empty_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/slide_down_empty.glsl"
+    }
+}
+
+mod horizontal_empty_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/horizontal_empty.glsl"
+    }
+}
+
+/// Also generates the [`Cell`](crate::cell::Cell) of cell.glsl for the Rust side
+pub mod color_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/color.glsl",
+        custom_derives: [Debug, Clone, Copy, PartialEq]
+    }
+}
+
+mod lenia_color_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "compute_shaders/lenia_color.glsl"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_constants_are_packed_by_id() {
+        let constants = KernelConstants::new(UVec2::new(3, 2)).with(6, 0.5f32);
+        assert_eq!(constants.0[..3], [3, 2, MatterId::EMPTY.0]);
+        assert_eq!(constants.0[6], 0.5f32.to_bits());
+        for (id, descriptor) in KernelConstants::descriptors().iter().enumerate() {
+            assert_eq!(descriptor.constant_id, id as u32);
+            assert_eq!(descriptor.offset as usize, 4 * id);
+            assert_eq!(descriptor.size, 4);
+        }
+    }
+}
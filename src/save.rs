@@ -0,0 +1,280 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use bevy::math::UVec2;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::{
+    cell::Cell,
+    matter::{MatterRegistry, MATTER_DEFINITIONS_PATH},
+    MAX_CANVAS_SIZE,
+};
+
+/// Path used by the save & load shortcuts unless changed in the gui
+pub const DEFAULT_SAVE_PATH: &str = "saves/canvas.cas";
+
+/// Identifies canvas save files
+const MAGIC: [u8; 4] = *b"CAS\0";
+/// Bumped whenever the layout of save files changes
+const FORMAT_VERSION: u32 = 3;
+/// Fields of a saved cell, see [`CanvasSave`]
+const CELL_BYTES: usize = 20;
+
+/// Errors from reading, writing and validating canvas saves
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    NotASave,
+    UnsupportedVersion(u32),
+    InvalidCanvasSize(UVec2),
+    RegistryMismatch,
+    InvalidMatter(u32),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "{}", e),
+            SaveError::NotASave => write!(f, "not a canvas save file"),
+            SaveError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save format version {}", version)
+            }
+            SaveError::InvalidCanvasSize(size) => {
+                write!(f, "invalid canvas size {}x{}", size.x, size.y)
+            }
+            SaveError::RegistryMismatch => write!(
+                f,
+                "saved with different matter definitions than in {}",
+                MATTER_DEFINITIONS_PATH
+            ),
+            SaveError::InvalidMatter(id) => write!(f, "unknown matter id {}", id),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError {
+    fn from(e: std::io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+/// Saved cell grid. On disk a small uncompressed header (magic, format version, canvas size,
+/// matter registry version, seed and step counter) is followed by the zlib compressed grid, each
+/// cell as its fields in the order of cell.glsl, little endian. Grids are mostly runs of the same
+/// matter, so they compress well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanvasSave {
+    pub canvas_size: UVec2,
+    /// [`MatterRegistry::version`] of the matters the grid refers to
+    pub registry_version: u64,
+    /// Seed and steps reproduce the rest of the run
+    pub seed: u32,
+    pub steps: u64,
+    pub cells: Vec<Cell>,
+}
+
+impl CanvasSave {
+    /// Write to a file, creating its directory if needed
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SaveError> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read from a file. The result still needs to be validated against the matter registry.
+    pub fn load(path: impl AsRef<Path>) -> Result<CanvasSave, SaveError> {
+        CanvasSave::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<(), SaveError> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&self.canvas_size.x.to_le_bytes())?;
+        writer.write_all(&self.canvas_size.y.to_le_bytes())?;
+        writer.write_all(&self.registry_version.to_le_bytes())?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&self.steps.to_le_bytes())?;
+        let mut encoder = ZlibEncoder::new(writer, Compression::default());
+        for cell in self.cells.iter() {
+            encoder.write_all(&cell.matter.to_le_bytes())?;
+            encoder.write_all(&cell.temperature.to_le_bytes())?;
+            encoder.write_all(&cell.velocity.to_le_bytes())?;
+            encoder.write_all(&cell.lifetime_variation.to_le_bytes())?;
+            encoder.write_all(&cell.state.to_le_bytes())?;
+        }
+        encoder.finish()?;
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<CanvasSave, SaveError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(SaveError::NotASave);
+        }
+        let version = read_u32(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(SaveError::UnsupportedVersion(version));
+        }
+        let canvas_size = UVec2::new(read_u32(&mut reader)?, read_u32(&mut reader)?);
+        if !(1..=MAX_CANVAS_SIZE).contains(&canvas_size.x)
+            || !(1..=MAX_CANVAS_SIZE).contains(&canvas_size.y)
+        {
+            return Err(SaveError::InvalidCanvasSize(canvas_size));
+        }
+        let registry_version = read_u64(&mut reader)?;
+        let seed = read_u32(&mut reader)?;
+        let steps = read_u64(&mut reader)?;
+        let mut bytes = vec![0; (canvas_size.x * canvas_size.y) as usize * CELL_BYTES];
+        ZlibDecoder::new(reader).read_exact(&mut bytes)?;
+        let cells = bytes
+            .chunks_exact(CELL_BYTES)
+            .map(|mut chunk| {
+                Ok(Cell {
+                    matter: read_u32(&mut chunk)?,
+                    temperature: f32::from_bits(read_u32(&mut chunk)?),
+                    velocity: read_u32(&mut chunk)?,
+                    lifetime_variation: read_u32(&mut chunk)?,
+                    state: f32::from_bits(read_u32(&mut chunk)?),
+                })
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(CanvasSave {
+            canvas_size,
+            registry_version,
+            seed,
+            steps,
+            cells,
+        })
+    }
+
+    /// Check that the grid refers to the matters of `registry`
+    pub fn validate(&self, registry: &MatterRegistry) -> Result<(), SaveError> {
+        if self.registry_version != registry.version() {
+            return Err(SaveError::RegistryMismatch);
+        }
+        let matter_count = registry.matters().count() as u32;
+        match self.cells.iter().find(|cell| cell.matter >= matter_count) {
+            Some(cell) => Err(SaveError::InvalidMatter(cell.matter)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec2;
+
+    use super::*;
+    use crate::matter::MatterId;
+
+    fn test_save(registry: &MatterRegistry) -> CanvasSave {
+        let canvas_size = UVec2::new(64, 48);
+        CanvasSave {
+            canvas_size,
+            registry_version: registry.version(),
+            seed: 99,
+            steps: 1234,
+            cells: (0..canvas_size.x * canvas_size.y)
+                .map(|i| {
+                    let mut cell = Cell::new(MatterId(if i < 1000 { 1 } else { i % 3 }));
+                    cell.temperature = (i % 7) as f32 * 100.0 - 50.0;
+                    cell.set_velocity(Vec2::new(0.0, -1.5));
+                    cell.set_lifetime(i % 5);
+                    cell.lifetime_variation |= (i % 11) << 24;
+                    cell.state = (i % 4) as f32 / 4.0;
+                    cell
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let save = test_save(&registry);
+        let mut bytes = vec![];
+        save.write(&mut bytes).unwrap();
+        assert!(bytes.len() < save.cells.len() * CELL_BYTES);
+        let loaded = CanvasSave::read(bytes.as_slice()).unwrap();
+        assert_eq!(loaded, save);
+        loaded.validate(&registry).unwrap();
+        // Everything but the matter survives too, e.g. the states of Lenia
+        let cell = loaded.cells[1234];
+        assert_eq!(cell.temperature, 150.0);
+        assert_eq!(cell.velocity(), Vec2::new(0.0, -1.5));
+        assert_eq!(cell.lifetime(), 4);
+        assert_eq!(cell.color_variation(), 2);
+        assert_eq!(cell.state, 0.5);
+    }
+
+    #[test]
+    fn rejects_older_versions() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let mut bytes = vec![];
+        test_save(&registry).write(&mut bytes).unwrap();
+        bytes[4..8].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(
+            CanvasSave::read(bytes.as_slice()),
+            Err(SaveError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let result = CanvasSave::read(b"(matters: [])".as_slice());
+        assert!(matches!(result, Err(SaveError::NotASave)));
+    }
+
+    #[test]
+    fn rejects_truncated_grid() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let mut bytes = vec![];
+        test_save(&registry).write(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        assert!(matches!(
+            CanvasSave::read(bytes.as_slice()),
+            Err(SaveError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_changed_matters() {
+        let registry = MatterRegistry::load(MATTER_DEFINITIONS_PATH).unwrap();
+        let save = test_save(&registry);
+        let other = MatterRegistry::from_ron(
+            r#"(matters: [
+                (name: "empty", color: 0, state: Empty, density: 0.0),
+                (name: "sand", color: 0, state: Powder, density: 1.6),
+            ])"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            save.validate(&other),
+            Err(SaveError::RegistryMismatch)
+        ));
+    }
+}
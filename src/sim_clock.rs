@@ -0,0 +1,113 @@
+use bevy::prelude::Resource;
+
+/// Upper bound of steps per frame, so that a slow frame doesn't make the next ones slower still
+pub const MAX_STEPS_PER_FRAME: u32 = 64;
+
+/// How steps are scheduled when not paused
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockMode {
+    /// `steps_per_frame` steps every frame, simulation speed depending on frame rate
+    PerFrame,
+    /// `steps_per_second` steps per second regardless of frame rate
+    FixedTimestep,
+}
+
+/// Decides how many simulation steps to run each frame
+#[derive(Debug, Clone, Resource)]
+pub struct SimClock {
+    pub paused: bool,
+    pub mode: ClockMode,
+    pub steps_per_frame: u32,
+    pub steps_per_second: f32,
+    /// Time not yet consumed by fixed timestep steps
+    accumulator: f32,
+    single_steps: u32,
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        SimClock {
+            paused: false,
+            mode: ClockMode::PerFrame,
+            steps_per_frame: 1,
+            steps_per_second: 60.0,
+            accumulator: 0.0,
+            single_steps: 0,
+        }
+    }
+}
+
+impl SimClock {
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// Pause and run exactly one step on the next frame
+    pub fn step_once(&mut self) {
+        self.paused = true;
+        self.single_steps += 1;
+    }
+
+    /// Steps to run for a frame that took `delta_seconds`
+    pub fn advance(&mut self, delta_seconds: f32) -> u32 {
+        let single_steps = std::mem::take(&mut self.single_steps);
+        if self.paused {
+            self.accumulator = 0.0;
+            return single_steps;
+        }
+        let steps = match self.mode {
+            ClockMode::PerFrame => self.steps_per_frame,
+            ClockMode::FixedTimestep => {
+                let timestep = 1.0 / self.steps_per_second.max(f32::EPSILON);
+                self.accumulator += delta_seconds;
+                let steps = (self.accumulator / timestep) as u32;
+                if steps > MAX_STEPS_PER_FRAME {
+                    // Too slow to keep up, drop the backlog instead of accumulating it
+                    self.accumulator = 0.0;
+                } else {
+                    self.accumulator -= steps as f32 * timestep;
+                }
+                steps
+            }
+        };
+        (steps + single_steps).min(MAX_STEPS_PER_FRAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paused_clock_only_single_steps() {
+        let mut clock = SimClock {
+            steps_per_frame: 4,
+            ..Default::default()
+        };
+        assert_eq!(clock.advance(0.016), 4);
+        clock.toggle_pause();
+        assert_eq!(clock.advance(0.016), 0);
+        clock.step_once();
+        clock.step_once();
+        assert_eq!(clock.advance(0.016), 2);
+        assert_eq!(clock.advance(0.016), 0);
+    }
+
+    #[test]
+    fn fixed_timestep_is_independent_of_frame_rate() {
+        let mut clock = SimClock {
+            mode: ClockMode::FixedTimestep,
+            steps_per_second: 100.0,
+            ..Default::default()
+        };
+        let steps_at = |clock: &mut SimClock, fps: u32| -> u32 {
+            (0..fps).map(|_| clock.advance(1.0 / fps as f32)).sum()
+        };
+        // One second at different frame rates, up to rounding of the last step
+        assert!((99..=100).contains(&steps_at(&mut clock.clone(), 30)));
+        assert!((99..=100).contains(&steps_at(&mut clock.clone(), 144)));
+        // A long frame runs at most the maximum and drops the rest
+        assert_eq!(clock.advance(10.0), MAX_STEPS_PER_FRAME);
+        assert_eq!(clock.advance(0.0), 0);
+    }
+}
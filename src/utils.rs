@@ -11,8 +11,6 @@ use vulkano::{
     shader::{EntryPoint, ShaderStages, SpecializationConstants},
 };
 
-use crate::{CANVAS_SIZE_X, CANVAS_SIZE_Y};
-
 /// Descriptor set layout binding information for storage buffer
 pub fn storage_buffer_desc() -> DescriptorSetLayoutBinding {
     DescriptorSetLayoutBinding {
@@ -96,23 +94,38 @@ impl MousePos {
 
     /// Converts world position to canvas position:
     /// Inverts y and adds half canvas to the position (pixel units)
-    pub fn canvas_pos(&self) -> Vec2 {
-        self.world + Vec2::new(CANVAS_SIZE_X as f32 / 2.0, CANVAS_SIZE_Y as f32 / 2.0)
+    pub fn canvas_pos(&self, canvas_size: UVec2) -> Vec2 {
+        self.world + canvas_size.as_vec2() / 2.0
+    }
+
+    /// Canvas cell under the mouse
+    pub fn canvas_cell(&self, canvas_size: UVec2) -> IVec2 {
+        self.canvas_pos(canvas_size).round().as_ivec2()
     }
 }
 
-/// Gets a line of canvas coordinates between previous and current mouse position
-pub fn get_canvas_line(prev: Option<MousePos>, current: MousePos) -> Vec<IVec2> {
-    let canvas_pos = current.canvas_pos();
-    let prev = if let Some(prev) = prev {
-        prev.canvas_pos()
-    } else {
-        canvas_pos
-    };
-    line_drawing::Bresenham::new(
-        (prev.x.round() as i32, prev.y.round() as i32),
-        (canvas_pos.x.round() as i32, canvas_pos.y.round() as i32),
-    )
-    .map(|pos| IVec2::new(pos.0, pos.1))
-    .collect::<Vec<IVec2>>()
+/// Converts world coordinates to window coordinates with origin at top left, the inverse of
+/// [`cursor_to_world`]
+pub fn world_to_window(window: &Window, camera_pos: Vec2, camera_scale: f32, world: Vec2) -> Vec2 {
+    let pos =
+        (world + camera_pos) / camera_scale + Vec2::new(window.width(), window.height()) / 2.0;
+    Vec2::new(pos.x, window.height() - pos.y)
+}
+
+/// Gets the segment of canvas cells from previous to current mouse position
+pub fn get_canvas_segment(
+    prev: Option<MousePos>,
+    current: MousePos,
+    canvas_size: UVec2,
+) -> [IVec2; 2] {
+    let current = current.canvas_cell(canvas_size);
+    let prev = prev.map_or(current, |prev| prev.canvas_cell(canvas_size));
+    [prev, current]
+}
+
+/// PCG hash from "Hash Functions for GPU Rendering" (Jarzynski & Olano, 2020)
+pub fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
 }